@@ -0,0 +1,217 @@
+// Original copyright notice
+// Copyright (c) 2023-, Germano Rizzo <oss /AT/ germanorizzo /DOT/ it>
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+// This work was inspired by sqliterg by Germano Rizzo and heavily modified to suite mysql.
+// Modifications c) 2023-, Nexon Asia Pacific also under apache 2.0 license
+
+use std::{
+    fs::{self, File, OpenOptions},
+    io::{self, Write},
+    path::{Path, PathBuf},
+    sync::Mutex,
+};
+
+use actix_web::HttpRequest;
+use chrono::{SecondsFormat, Utc};
+use lazy_static::lazy_static;
+use ring::digest;
+use serde::Deserialize;
+use serde_json::{json, Value};
+
+use crate::{auth, logic::RequestContext, metrics};
+
+// Principal for requests that weren't authenticated by anything we trust
+pub const ANONYMOUS: &str = "anonymous";
+
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(rename_all = "camelCase", deny_unknown_fields)]
+pub struct AuditSettings {
+    pub path: String,
+    // The file is rotated once it grows past this, 0 never rotates
+    #[serde(default = "default_max_size_mb")]
+    pub max_size_mb: u64,
+    // Rotated files kept next to the live one, as path.1 (newest) to path.N
+    #[serde(default = "default_keep")]
+    pub keep: u32,
+    // Header carrying the user authenticated by the reverse proxy in front of us. Only set
+    // this when the proxy strips the header from client requests.
+    pub principal_header: Option<String>,
+}
+
+fn default_max_size_mb() -> u64 {
+    100
+}
+
+fn default_keep() -> u32 {
+    10
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Outcome {
+    Commit,
+    Rollback,
+    CommitFailed,
+}
+
+impl Outcome {
+    fn as_str(&self) -> &'static str {
+        match self {
+            Outcome::Commit => "commit",
+            Outcome::Rollback => "rollback",
+            Outcome::CommitFailed => "commitFailed",
+        }
+    }
+}
+
+// A statement item that ran, kept until the transaction is over
+#[derive(Debug)]
+pub struct AuditEntry {
+    pub index: usize,
+    pub tag: Option<String>,
+    pub sql: String,
+    pub param_hash: Option<String>,
+    pub rows_affected: u64,
+    pub success: bool,
+}
+
+// SHA-256 of the values as sent, so a statement can be matched against the client's
+// records without the audit log holding the data itself
+pub fn param_hash(values: Option<&Value>) -> Option<String> {
+    values.map(|v| hex::encode(digest::digest(&digest::SHA256, v.to_string().as_bytes())))
+}
+
+pub struct AuditLog {
+    path: PathBuf,
+    file: File,
+    size: u64,
+    max_size: u64,
+    keep: u32,
+}
+
+impl AuditLog {
+    pub fn open(settings: &AuditSettings) -> io::Result<AuditLog> {
+        let path = PathBuf::from(shellexpand::tilde(&settings.path).into_owned());
+        let file = append(&path)?;
+        let size = file.metadata()?.len();
+        Ok(AuditLog {
+            path,
+            file,
+            size,
+            max_size: settings.max_size_mb * 1024 * 1024,
+            keep: settings.keep,
+        })
+    }
+
+    // Lines are written whole with a single write, and rotation only happens between them
+    pub fn write(&mut self, record: &Value) -> io::Result<()> {
+        let mut line = record.to_string();
+        line.push('\n');
+        if self.max_size > 0 && self.size > 0 && self.size + line.len() as u64 > self.max_size {
+            self.rotate()?;
+        }
+        self.file.write_all(line.as_bytes())?;
+        self.file.flush()?;
+        self.size += line.len() as u64;
+        Ok(())
+    }
+
+    fn rotate(&mut self) -> io::Result<()> {
+        let rotated = |n: u32| {
+            let mut name = self.path.clone().into_os_string();
+            name.push(format!(".{}", n));
+            PathBuf::from(name)
+        };
+        if self.keep == 0 {
+            fs::remove_file(&self.path)?;
+        } else {
+            for n in (1..self.keep).rev() {
+                if rotated(n).exists() {
+                    fs::rename(rotated(n), rotated(n + 1))?;
+                }
+            }
+            fs::rename(&self.path, rotated(1))?;
+        }
+        self.file = append(&self.path)?;
+        self.size = 0;
+        Ok(())
+    }
+}
+
+fn append(path: &Path) -> io::Result<File> {
+    OpenOptions::new().create(true).append(true).open(path)
+}
+
+lazy_static! {
+    static ref AUDIT_LOG: Mutex<Option<AuditLog>> = Mutex::new(None);
+}
+
+// None stops writing, e.g. when a reloaded config drops the audit section
+pub fn init(settings: Option<&AuditSettings>) -> io::Result<()> {
+    let audit_log = settings.map(AuditLog::open).transpose()?;
+    *AUDIT_LOG.lock().unwrap() = audit_log;
+    Ok(())
+}
+
+// Whoever the listener authenticated, then a client certificate, then the proxy header.
+// Only for attribution, roles go by auth::authenticated.
+pub fn principal(req: &HttpRequest, settings: Option<&AuditSettings>) -> String {
+    if let Some(principal) = auth::authenticated(req) {
+        return principal;
+    }
+    settings
+        .and_then(|s| s.principal_header.as_ref())
+        .and_then(|name| req.headers().get(name.as_str()))
+        .and_then(|h| h.to_str().ok())
+        .filter(|p| !p.is_empty())
+        .unwrap_or(ANONYMOUS)
+        .to_string()
+}
+
+// Called once the transaction is over, so the outcome is the real one
+pub(crate) fn record(ctx: &RequestContext<'_>, entries: &[AuditEntry], outcome: Outcome) {
+    if entries.is_empty() {
+        return;
+    }
+    let mut lock = AUDIT_LOG.lock().unwrap();
+    let Some(audit_log) = lock.as_mut() else {
+        return;
+    };
+    let timestamp = Utc::now().to_rfc3339_opts(SecondsFormat::Millis, true);
+    for entry in entries {
+        let record = json!({
+            "timestamp": timestamp,
+            "principal": ctx.principal,
+            "requestId": ctx.request_id,
+            "profile": ctx.profile_name,
+            "item": entry.index,
+            "tag": entry.tag,
+            "sql": entry.sql,
+            "paramHash": entry.param_hash,
+            "rowsAffected": entry.rows_affected,
+            "success": entry.success,
+            "outcome": outcome.as_str(),
+        });
+        if let Err(e) = audit_log.write(&record) {
+            // The transaction is already over, all we can do is make noise
+            metrics::observe_error(ctx.profile_name, "audit");
+            log::error!(
+                request_id = ctx.request_id,
+                profile = ctx.profile_name;
+                "Could not write audit log: {}", e
+            );
+            return;
+        }
+    }
+}
@@ -0,0 +1,154 @@
+// Original copyright notice
+// Copyright (c) 2023-, Germano Rizzo <oss /AT/ germanorizzo /DOT/ it>
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+// This work was inspired by sqliterg by Germano Rizzo and heavily modified to suite mysql.
+// Modifications c) 2023-, Nexon Asia Pacific also under apache 2.0 license
+
+use std::{collections::BTreeMap, future::Future};
+
+use actix_web::{
+    body::EitherBody,
+    dev::{Service, ServiceRequest, ServiceResponse},
+    http::header::{self, Header},
+    HttpMessage, HttpRequest, HttpResponse,
+};
+use actix_web_httpauth::headers::authorization::{Authorization, Bearer};
+use ring::digest;
+use serde::Deserialize;
+
+use crate::{statics::CONFIG, tls::ClientPrincipal};
+
+// Probes have no credentials, so these are answered on any listener. So is the console
+// page, which holds no data: a browser can't send a token when opening it, the page sends
+// one with every request it makes.
+const OPEN_PATHS: &[&str] = &["/healthz", "/readyz", "/console"];
+
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub enum AuthMode {
+    #[default]
+    None,
+    // Authorization: Bearer with one of the configured tokens
+    Token,
+    // A verified client certificate, needs tls.clientAuth
+    Mtls,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(rename_all = "camelCase", deny_unknown_fields)]
+pub struct AuthSettings {
+    // Principal to bearer token, for listeners with auth=token
+    #[serde(default)]
+    pub tokens: BTreeMap<String, String>,
+}
+
+impl AuthSettings {
+    pub fn validate(&self) -> Result<(), String> {
+        match self.tokens.iter().find(|(_, token)| token.is_empty()) {
+            Some((principal, _)) => Err(format!("auth: token for '{}' is empty", principal)),
+            None => Ok(()),
+        }
+    }
+
+    fn principal(&self, token: &str) -> Option<&str> {
+        // Compares digests, so the time taken doesn't depend on how much of a token matched
+        let given = digest::digest(&digest::SHA256, token.as_bytes());
+        let mut found = None;
+        for (principal, expected) in &self.tokens {
+            let expected = digest::digest(&digest::SHA256, expected.as_bytes());
+            let diff = given
+                .as_ref()
+                .iter()
+                .zip(expected.as_ref())
+                .fold(0, |acc, (a, b)| acc | (a ^ b));
+            if diff == 0 && found.is_none() {
+                found = Some(principal.as_str());
+            }
+        }
+        found
+    }
+}
+
+// Who the listener authenticated, picked up by the audit log
+#[derive(Debug, Clone)]
+pub struct Principal(pub String);
+
+// Whoever the listener or a client certificate vouches for, the only principal roles are
+// given to. Headers the client sets never count here.
+pub fn authenticated(req: &HttpRequest) -> Option<String> {
+    if let Some(Principal(principal)) = req.extensions().get::<Principal>() {
+        return Some(principal.clone());
+    }
+    req.conn_data::<ClientPrincipal>()
+        .map(|ClientPrincipal(principal)| principal.clone())
+}
+
+fn authenticate(mode: AuthMode, req: &ServiceRequest) -> Result<Option<String>, HttpResponse> {
+    match mode {
+        AuthMode::None => Ok(None),
+        AuthMode::Token => {
+            let config = CONFIG.read().unwrap().clone();
+            Authorization::<Bearer>::parse(req.request())
+                .ok()
+                .and_then(|auth| {
+                    config
+                        .auth
+                        .as_ref()?
+                        .principal(auth.as_ref().token())
+                        .map(str::to_string)
+                })
+                .map(Some)
+                .ok_or_else(|| {
+                    HttpResponse::Unauthorized()
+                        .insert_header((header::WWW_AUTHENTICATE, "Bearer"))
+                        .body("missing or unknown bearer token")
+                })
+        }
+        AuthMode::Mtls => req
+            .conn_data::<ClientPrincipal>()
+            .map(|ClientPrincipal(principal)| Some(principal.clone()))
+            .ok_or_else(|| HttpResponse::Unauthorized().body("client certificate required")),
+    }
+}
+
+// Turns away requests that don't carry what the listener's auth mode asks for
+pub fn middleware<S, B>(
+    mode: AuthMode,
+    req: ServiceRequest,
+    srv: &S,
+) -> impl Future<Output = Result<ServiceResponse<EitherBody<B>>, actix_web::Error>>
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = actix_web::Error>,
+{
+    let outcome = if OPEN_PATHS.contains(&req.path()) {
+        Ok(None)
+    } else {
+        authenticate(mode, &req)
+    };
+    let next = match outcome {
+        Ok(principal) => {
+            if let Some(principal) = principal {
+                req.extensions_mut().insert(Principal(principal));
+            }
+            Ok(srv.call(req))
+        }
+        Err(res) => Err(req.into_response(res)),
+    };
+    async move {
+        match next {
+            Ok(fut) => fut.await.map(ServiceResponse::map_into_left_body),
+            Err(res) => Ok(res.map_into_right_body()),
+        }
+    }
+}
@@ -0,0 +1,180 @@
+// Original copyright notice
+// Copyright (c) 2023-, Germano Rizzo <oss /AT/ germanorizzo /DOT/ it>
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+// This work was inspired by sqliterg by Germano Rizzo and heavily modified to suite mysql.
+// Modifications c) 2023-, Nexon Asia Pacific also under apache 2.0 license
+
+use serde::Deserialize;
+use serde_json::Value;
+
+use crate::params::code_offsets;
+
+// How the results of a bulk insert are reported
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub enum BulkMode {
+    // One result per entry of valuesBatch, as without bulk
+    PerRow,
+    // A single result with the total rows affected and the first insert id
+    Aggregated,
+}
+
+// Most placeholders a single prepared statement can have
+const MAX_PLACEHOLDERS: usize = 65535;
+
+// A simple INSERT ... VALUES (?, ...) split around its row, so it can be repeated
+#[derive(Debug, PartialEq)]
+pub struct InsertTemplate {
+    prefix: String,
+    row: String,
+    suffix: String,
+    pub placeholders: usize,
+}
+
+impl InsertTemplate {
+    pub fn parse(sql: &str) -> Result<InsertTemplate, String> {
+        let not_simple = || {
+            "bulk needs a simple INSERT ... VALUES (?, ...) statement with placeholders only in the row".to_string()
+        };
+        let bytes = sql.as_bytes();
+        let offsets: Vec<usize> = code_offsets(sql)
+            .filter(|i| !bytes[*i].is_ascii_whitespace())
+            .collect();
+
+        let first = *offsets.first().ok_or_else(not_simple)?;
+        if !starts_with_keyword(sql, first, "INSERT") && !starts_with_keyword(sql, first, "REPLACE") {
+            return Err(not_simple());
+        }
+
+        // The keyword outside of any parenthesis, so a column called value doesn't match
+        let mut depth = 0;
+        let keyword = offsets
+            .iter()
+            .position(|i| {
+                match bytes[*i] {
+                    b'(' => depth += 1,
+                    b')' => depth -= 1,
+                    _ => {}
+                }
+                depth == 0
+                    && (starts_with_keyword(sql, *i, "VALUES") || starts_with_keyword(sql, *i, "VALUE"))
+            })
+            .ok_or_else(not_simple)?;
+        if offsets[..keyword].iter().any(|i| bytes[*i] == b'?') {
+            return Err(not_simple());
+        }
+
+        // First code byte after the keyword has to open the row
+        let keyword_end = offsets[keyword]
+            + if starts_with_keyword(sql, offsets[keyword], "VALUES") { 6 } else { 5 };
+        let open = offsets[keyword..]
+            .iter()
+            .position(|i| *i >= keyword_end)
+            .map(|p| p + keyword)
+            .ok_or_else(not_simple)?;
+        if bytes[offsets[open]] != b'(' {
+            return Err(not_simple());
+        }
+
+        let mut depth = 0;
+        let mut placeholders = 0;
+        let mut close = None;
+        for (p, i) in offsets.iter().enumerate().skip(open) {
+            match bytes[*i] {
+                b'(' => depth += 1,
+                b')' => {
+                    depth -= 1;
+                    if depth == 0 {
+                        close = Some(p);
+                        break;
+                    }
+                }
+                b'?' if sql[*i..].starts_with("?...") => return Err(not_simple()),
+                b'?' => placeholders += 1,
+                _ => {}
+            }
+        }
+        let close = close.ok_or_else(not_simple)?;
+
+        // Already several rows, or placeholders in an ON DUPLICATE KEY UPDATE
+        let rest = &offsets[close + 1..];
+        if rest.first().is_some_and(|i| bytes[*i] == b',') || rest.iter().any(|i| bytes[*i] == b'?') {
+            return Err(not_simple());
+        }
+
+        Ok(InsertTemplate {
+            prefix: sql[..offsets[open]].to_string(),
+            row: sql[offsets[open]..=offsets[close]].to_string(),
+            suffix: sql[offsets[close] + 1..].to_string(),
+            placeholders,
+        })
+    }
+
+    pub fn sql(&self, rows: usize) -> String {
+        let mut sql = String::with_capacity(
+            self.prefix.len() + (self.row.len() + 2) * rows + self.suffix.len(),
+        );
+        sql.push_str(&self.prefix);
+        for row in 0..rows {
+            if row > 0 {
+                sql.push_str(", ");
+            }
+            sql.push_str(&self.row);
+        }
+        sql.push_str(&self.suffix);
+        sql
+    }
+
+    // Splits the rows into chunks whose statement and parameters fit in max_packet.
+    // Sizes are estimated on the high side rather than computed exactly.
+    pub fn chunks(&self, rows: &[Value], max_packet: usize) -> Vec<usize> {
+        let budget = max_packet - max_packet / 10;
+        let max_rows = (MAX_PLACEHOLDERS / self.placeholders.max(1)).max(1);
+        let mut chunks = vec![];
+        let mut count = 0;
+        let mut size = self.prefix.len() + self.suffix.len();
+        for row in rows {
+            let row_size = self.row.len() + 2 + estimated_size(row);
+            if count > 0 && (count == max_rows || size + row_size > budget) {
+                chunks.push(count);
+                count = 0;
+                size = self.prefix.len() + self.suffix.len();
+            }
+            count += 1;
+            size += row_size;
+        }
+        if count > 0 {
+            chunks.push(count);
+        }
+        chunks
+    }
+}
+
+fn starts_with_keyword(sql: &str, at: usize, keyword: &str) -> bool {
+    let end = at + keyword.len();
+    let is_ident = |b: &u8| b.is_ascii_alphanumeric() || *b == b'_';
+    sql.get(at..end).is_some_and(|s| s.eq_ignore_ascii_case(keyword))
+        && !sql.as_bytes().get(end).is_some_and(is_ident)
+        && (at == 0 || !is_ident(&sql.as_bytes()[at - 1]))
+}
+
+// Length prefix and type bytes included
+fn estimated_size(value: &Value) -> usize {
+    match value {
+        Value::Array(items) => items.iter().map(estimated_size).sum(),
+        Value::String(s) => s.len() + 11,
+        _ => value.to_string().len() + 11,
+    }
+}
@@ -0,0 +1,253 @@
+// Original copyright notice
+// Copyright (c) 2023-, Germano Rizzo <oss /AT/ germanorizzo /DOT/ it>
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+// This work was inspired by sqliterg by Germano Rizzo and heavily modified to suite mysql.
+// Modifications c) 2023-, Nexon Asia Pacific also under apache 2.0 license
+
+use std::{
+    collections::{BTreeSet, HashMap},
+    io,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use actix_web::{web, HttpResponse};
+use lazy_static::lazy_static;
+use serde::Deserialize;
+use serde_json::{json, Value};
+
+use crate::{metrics, params::BindOptions, statics::CONFIG};
+
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize)]
+#[serde(rename_all = "camelCase", deny_unknown_fields)]
+pub struct CacheSettings {
+    // Memory all cached results may take together, oldest expiry evicted first
+    #[serde(default = "default_max_mb")]
+    pub max_mb: u64,
+    // Larger results are not cached at all
+    #[serde(default = "default_max_entry_kb")]
+    pub max_entry_kb: u64,
+}
+
+fn default_max_mb() -> u64 {
+    64
+}
+
+fn default_max_entry_kb() -> u64 {
+    1024
+}
+
+impl Default for CacheSettings {
+    fn default() -> Self {
+        CacheSettings {
+            max_mb: default_max_mb(),
+            max_entry_kb: default_max_entry_kb(),
+        }
+    }
+}
+
+struct Entry {
+    profile: String,
+    rows: Arc<Vec<Value>>,
+    tables: Vec<String>,
+    expires: Instant,
+    size: usize,
+}
+
+// Result sets of query items, keyed by profile, SQL and values
+#[derive(Default)]
+pub struct ResultCache {
+    entries: HashMap<String, Entry>,
+    // The same keys by expiry, soonest first, to purge and evict without a scan
+    expiries: BTreeSet<(Instant, String)>,
+    bytes: usize,
+}
+
+// Counts what is written and keeps none of it
+struct ByteCounter(usize);
+
+impl io::Write for ByteCounter {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.0 += buf.len();
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+// Sizes are the length of the key and the rows as JSON, close enough to what they hold
+pub fn size(key: &str, rows: &[Value]) -> usize {
+    let mut counter = ByteCounter(0);
+    serde_json::to_writer(&mut counter, rows).unwrap_or(());
+    key.len() + counter.0
+}
+
+impl ResultCache {
+    pub fn get(&mut self, key: &str) -> Option<Arc<Vec<Value>>> {
+        match self.entries.get(key) {
+            Some(entry) if entry.expires > Instant::now() => Some(entry.rows.clone()),
+            Some(_) => {
+                self.remove(key);
+                None
+            }
+            None => None,
+        }
+    }
+
+    pub fn insert(
+        &mut self,
+        key: String,
+        profile: &str,
+        rows: Vec<Value>,
+        tables: &[String],
+        ttl: Duration,
+        settings: CacheSettings,
+    ) {
+        let size = size(&key, &rows);
+        self.insert_sized(key, size, profile, rows, tables, ttl, settings);
+    }
+
+    // size is measured by the caller, so it needn't be under the lock
+    #[allow(clippy::too_many_arguments)]
+    fn insert_sized(
+        &mut self,
+        key: String,
+        size: usize,
+        profile: &str,
+        rows: Vec<Value>,
+        tables: &[String],
+        ttl: Duration,
+        settings: CacheSettings,
+    ) {
+        let max_bytes = (settings.max_mb * 1024 * 1024) as usize;
+        if size as u64 > settings.max_entry_kb * 1024 || size > max_bytes {
+            return;
+        }
+        self.remove(&key);
+        let now = Instant::now();
+        while let Some((expires, _)) = self.expiries.first() {
+            if *expires > now && self.bytes + size <= max_bytes {
+                break;
+            }
+            let (_, soonest) = self.expiries.pop_first().unwrap();
+            self.remove(&soonest);
+        }
+        let expires = now + ttl;
+        self.bytes += size;
+        self.expiries.insert((expires, key.clone()));
+        self.entries.insert(
+            key,
+            Entry {
+                profile: profile.to_string(),
+                rows: Arc::new(rows),
+                tables: tables.to_vec(),
+                expires,
+                size,
+            },
+        );
+    }
+
+    // Everything of the profile, or of all profiles, optionally only results tagged with
+    // one of the tables
+    pub fn invalidate(&mut self, profile: Option<&str>, tables: Option<&[String]>) -> usize {
+        let stale: Vec<String> = self
+            .entries
+            .iter()
+            .filter(|(_, entry)| profile.is_none_or(|p| entry.profile == p))
+            .filter(|(_, entry)| {
+                tables.is_none_or(|tables| {
+                    entry
+                        .tables
+                        .iter()
+                        .any(|t| tables.iter().any(|other| t.eq_ignore_ascii_case(other)))
+                })
+            })
+            .map(|(key, _)| key.clone())
+            .collect();
+        for key in &stale {
+            self.remove(key);
+        }
+        stale.len()
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    fn remove(&mut self, key: &str) {
+        if let Some(entry) = self.entries.remove(key) {
+            self.expiries.remove(&(entry.expires, key.to_string()));
+            self.bytes -= entry.size;
+        }
+    }
+}
+
+lazy_static! {
+    static ref CACHE: Mutex<ResultCache> = Mutex::new(ResultCache::default());
+}
+
+// Bind options are part of the key, as they change how values bind and results read
+pub fn key(profile: &str, sql: &str, values: Option<&Value>, bind_opts: &BindOptions) -> String {
+    format!(
+        "{}\n{}\n{}\n{:?}",
+        profile,
+        sql,
+        values.unwrap_or(&Value::Null),
+        bind_opts
+    )
+}
+
+pub fn get(profile: &str, key: &str) -> Option<Arc<Vec<Value>>> {
+    let rows = CACHE.lock().unwrap().get(key);
+    metrics::observe_cache(profile, rows.is_some());
+    rows
+}
+
+pub fn insert(key: String, profile: &str, rows: Vec<Value>, tables: &[String], ttl: Duration) {
+    let settings = CONFIG.read().unwrap().cache;
+    let size = size(&key, &rows);
+    CACHE
+        .lock()
+        .unwrap()
+        .insert_sized(key, size, profile, rows, tables, ttl, settings);
+}
+
+pub fn invalidate(profile: Option<&str>, tables: Option<&[String]>) -> usize {
+    CACHE.lock().unwrap().invalidate(profile, tables)
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Invalidation {
+    pub profile: Option<String>,
+    pub tables: Option<Vec<String>>,
+}
+
+// POST /admin/cache/invalidate, without a body everything goes
+pub async fn handler(body: Option<web::Json<Invalidation>>) -> HttpResponse {
+    let invalidation = body.map(web::Json::into_inner).unwrap_or_default();
+    let removed = invalidate(
+        invalidation.profile.as_deref(),
+        invalidation.tables.as_deref(),
+    );
+    log::info!("Invalidated {} cached result(s)", removed);
+    HttpResponse::Ok().json(json!({ "removed": removed }))
+}
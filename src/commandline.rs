@@ -16,8 +16,12 @@
 // This work was inspired by sqliterg by Germano Rizzo and heavily modified to suite mysql.
 // Modifications c) 2023-, Nexon Asia Pacific also under apache 2.0 license
 
+use std::{fmt, path::PathBuf};
+
 use clap::Parser;
 
+use crate::{auth::AuthMode, config::Config, cors::CorsPolicy, logging::LogFormat};
+
 #[derive(Debug, Parser)]
 #[command(author, version, about, long_about = None)]
 #[command(
@@ -38,12 +42,275 @@ pub struct AppConfig {
         default_value = "12321",
         help = "Port for the web service"
     )]
-    pub port: u16
-   
+    pub port: u16,
+    #[arg(
+        long,
+        value_name = "FILE",
+        help = "YAML config file with the connection profiles"
+    )]
+    pub config: Option<String>,
+    #[arg(
+        long,
+        value_name = "FORMAT",
+        default_value = "text",
+        help = "Log line format, levels are set with RUST_LOG"
+    )]
+    pub log_format: LogFormat,
+    #[arg(
+        long,
+        value_name = "PATH",
+        help = "Also listen on this unix socket"
+    )]
+    pub unix_socket: Option<String>,
+    #[arg(
+        long,
+        value_name = "MODE",
+        default_value = "660",
+        value_parser = parse_mode,
+        help = "Octal permissions of the unix socket"
+    )]
+    pub unix_socket_mode: u32,
+    #[arg(
+        long,
+        value_name = "UID[:GID]",
+        value_parser = parse_owner,
+        help = "Numeric owner and group of the unix socket"
+    )]
+    pub unix_socket_owner: Option<Owner>,
+    #[arg(
+        long,
+        requires = "unix_socket",
+        help = "Only listen on the unix socket, not on bind-host:port"
+    )]
+    pub no_tcp: bool,
+    #[arg(
+        long = "listen",
+        value_name = "SPEC",
+        value_parser = parse_listener,
+        conflicts_with_all = ["unix_socket", "no_tcp"],
+        help = "A listener as ADDR[;routes=GROUP,...][;auth=none|token|mtls][;cors=POLICY|none], \
+                repeatable. ADDR is HOST:PORT, https://HOST:PORT or unix:PATH, GROUP one of api, \
+                health, metrics, console, static, admin, POLICY one of corsPolicies in the config. \
+                Replaces bind-host, port and unix-socket"
+    )]
+    pub listen: Vec<Listener>,
+    #[arg(
+        long,
+        value_name = "SECS",
+        default_value = "30",
+        help = "On SIGTERM, how long running transactions get to finish before they are rolled back"
+    )]
+    pub drain_timeout: u64
 }
 
-pub fn parse_cli() -> AppConfig {
-    let ret = AppConfig::parse();
+#[derive(Debug, Clone, PartialEq)]
+pub enum ListenAddress {
+    Tcp { addr: String, tls: bool },
+    Unix(PathBuf),
+}
+
+// Which routes a listener serves
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Routes {
+    pub api: bool,
+    pub health: bool,
+    pub metrics: bool,
+    pub console: bool,
+    pub static_files: bool,
+    pub admin: bool,
+}
+
+impl Routes {
+    // Admin routes change the running gateway and the console runs any SQL, so they are
+    // only served when asked for
+    pub const DEFAULT: Routes = Routes {
+        api: true,
+        health: true,
+        metrics: true,
+        console: false,
+        static_files: true,
+        admin: false,
+    };
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Listener {
+    pub address: ListenAddress,
+    pub routes: Routes,
+    pub auth: AuthMode,
+    pub cors: CorsPolicy,
+}
+
+impl AppConfig {
+    // The --listen list, or the single listener described by the older flags
+    pub fn listeners(&self, tls_configured: bool) -> Vec<Listener> {
+        if !self.listen.is_empty() {
+            return self.listen.clone();
+        }
+        let mut listeners = vec![];
+        if !self.no_tcp {
+            listeners.push(Listener {
+                address: ListenAddress::Tcp {
+                    addr: format!("{}:{}", self.bind_host, self.port),
+                    tls: tls_configured,
+                },
+                routes: Routes::DEFAULT,
+                auth: AuthMode::None,
+                cors: CorsPolicy::Global,
+            });
+        }
+        if let Some(path) = &self.unix_socket {
+            listeners.push(Listener {
+                address: ListenAddress::Unix(PathBuf::from(path)),
+                routes: Routes::DEFAULT,
+                auth: AuthMode::None,
+                cors: CorsPolicy::Global,
+            });
+        }
+        listeners
+    }
+}
+
+impl Listener {
+    // Checks the parts of the spec that depend on the config file
+    pub fn check(&self, config: &Config) -> Result<(), String> {
+        let tls = matches!(self.address, ListenAddress::Tcp { tls: true, .. });
+        if tls && config.tls.is_none() {
+            return Err(format!("{}: https needs tls in the config", self));
+        }
+        if let CorsPolicy::Named(name) = &self.cors {
+            if !config.cors_policies.contains_key(name) {
+                return Err(format!("{}: cors={} is not in corsPolicies", self, name));
+            }
+        }
+        if self.routes.console && self.auth == AuthMode::None {
+            return Err(format!("{}: the console needs auth=token or auth=mtls", self));
+        }
+        match self.auth {
+            AuthMode::None => Ok(()),
+            AuthMode::Token if config.auth.as_ref().is_none_or(|a| a.tokens.is_empty()) => {
+                Err(format!("{}: auth=token needs auth.tokens in the config", self))
+            }
+            AuthMode::Token => Ok(()),
+            AuthMode::Mtls if !tls || config.tls.as_ref().is_none_or(|t| t.client_auth.is_none()) => {
+                Err(format!("{}: auth=mtls needs https and tls.clientAuth", self))
+            }
+            AuthMode::Mtls => Ok(()),
+        }
+    }
+}
 
-    ret
+impl fmt::Display for Listener {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match &self.address {
+            ListenAddress::Tcp { addr, tls: true } => write!(f, "https://{}", addr),
+            ListenAddress::Tcp { addr, tls: false } => write!(f, "{}", addr),
+            ListenAddress::Unix(path) => write!(f, "unix:{}", path.display()),
+        }
+    }
+}
+
+fn parse_listener(spec: &str) -> Result<Listener, String> {
+    let mut parts = spec.split(';');
+    let addr = parts.next().unwrap_or_default().trim();
+    let address = if let Some(path) = addr.strip_prefix("unix:") {
+        ListenAddress::Unix(PathBuf::from(path))
+    } else if let Some(addr) = addr.strip_prefix("https://") {
+        ListenAddress::Tcp { addr: addr.to_string(), tls: true }
+    } else {
+        ListenAddress::Tcp {
+            addr: addr.strip_prefix("http://").unwrap_or(addr).to_string(),
+            tls: false,
+        }
+    };
+    if matches!(&address, ListenAddress::Tcp { addr, .. } if !addr.contains(':'))
+        || matches!(&address, ListenAddress::Unix(path) if path.as_os_str().is_empty())
+    {
+        return Err(format!("'{}' is not HOST:PORT, https://HOST:PORT or unix:PATH", addr));
+    }
+
+    let mut listener = Listener {
+        address,
+        routes: Routes::DEFAULT,
+        auth: AuthMode::None,
+        cors: CorsPolicy::Global,
+    };
+    for option in parts {
+        match option.trim().split_once('=') {
+            Some(("routes", groups)) => {
+                let mut routes = Routes {
+                    api: false,
+                    health: false,
+                    metrics: false,
+                    console: false,
+                    static_files: false,
+                    admin: false,
+                };
+                for group in groups.split(',') {
+                    match group.trim() {
+                        "api" => routes.api = true,
+                        "health" => routes.health = true,
+                        "metrics" => routes.metrics = true,
+                        "console" => routes.console = true,
+                        "static" => routes.static_files = true,
+                        "admin" => routes.admin = true,
+                        other => return Err(format!("unknown route group '{}'", other)),
+                    }
+                }
+                listener.routes = routes;
+            }
+            Some(("auth", mode)) => {
+                listener.auth = match mode.trim() {
+                    "none" => AuthMode::None,
+                    "token" => AuthMode::Token,
+                    "mtls" => AuthMode::Mtls,
+                    other => return Err(format!("unknown auth '{}'", other)),
+                }
+            }
+            Some(("cors", policy)) => {
+                listener.cors = match policy.trim() {
+                    "none" => CorsPolicy::Off,
+                    "" => return Err("cors needs a policy name or none".to_string()),
+                    name => CorsPolicy::Named(name.to_string()),
+                }
+            }
+            _ => return Err(format!("unknown listener option '{}'", option)),
+        }
+    }
+    Ok(listener)
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Owner {
+    pub uid: Option<u32>,
+    pub gid: Option<u32>,
+}
+
+fn parse_mode(mode: &str) -> Result<u32, String> {
+    u32::from_str_radix(mode, 8)
+        .ok()
+        .filter(|mode| *mode <= 0o777)
+        .ok_or_else(|| format!("'{}' is not an octal mode like 660", mode))
+}
+
+// "1000", "1000:1000" or ":1000" to only change the group
+fn parse_owner(owner: &str) -> Result<Owner, String> {
+    let id = |s: &str| -> Result<Option<u32>, String> {
+        if s.is_empty() {
+            Ok(None)
+        } else {
+            s.parse()
+                .map(Some)
+                .map_err(|_| format!("'{}' is not a numeric id", s))
+        }
+    };
+    let (uid, gid) = owner.split_once(':').unwrap_or((owner, ""));
+    Ok(Owner {
+        uid: id(uid)?,
+        gid: id(gid)?,
+    })
+}
+
+pub fn parse_cli() -> AppConfig {
+    AppConfig::parse()
 }
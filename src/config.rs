@@ -0,0 +1,202 @@
+// Original copyright notice
+// Copyright (c) 2023-, Germano Rizzo <oss /AT/ germanorizzo /DOT/ it>
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+// This work was inspired by sqliterg by Germano Rizzo and heavily modified to suite mysql.
+// Modifications c) 2023-, Nexon Asia Pacific also under apache 2.0 license
+
+use std::{collections::BTreeMap, fs};
+
+use serde::Deserialize;
+
+use crate::{
+    audit::AuditSettings, auth::AuthSettings, cache::CacheSettings, cors::CorsSettings, datetime::DateTimeSettings, failover::FailoverSettings,
+    replicas::ReplicaSettings, rest::RestSettings, static_files::StaticFilesSettings, stored_queries::StoredQuery, telemetry::TelemetrySettings, tls::TlsSettings,
+};
+
+// A named connection target, selected with the profile header instead of sending the
+// connection string on every request
+#[derive(Debug, Clone, Deserialize)]
+#[serde(rename_all = "camelCase", deny_unknown_fields)]
+pub struct Profile {
+    pub connection_string: String,
+    // Path of the server's unix socket, used instead of the host in the connection string
+    pub socket: Option<String>,
+    // Whether /readyz fails when this profile is down
+    #[serde(default = "default_required")]
+    pub required: bool,
+    #[serde(default)]
+    pub datetime: DateTimeSettings,
+    // Overrides slowQuery.thresholdMs for this profile
+    pub slow_query_ms: Option<u64>,
+    // Tables served under /db/{profile}/tables
+    pub rest: Option<RestSettings>,
+    // Read-only queries run with GET /db/{profile}/q/{name}
+    #[serde(default)]
+    pub queries: BTreeMap<String, StoredQuery>,
+    // Other hosts of the primary, used when connectionString can't be reached
+    pub failover: Option<FailoverSettings>,
+    // Read-only transactions are spread across these
+    pub replicas: Option<ReplicaSettings>,
+}
+
+fn default_required() -> bool {
+    true
+}
+
+impl Profile {
+    // What the pool is opened with and keyed by
+    pub fn connection_url(&self) -> String {
+        match &self.socket {
+            Some(socket) => {
+                let separator = if self.connection_string.contains('?') { '&' } else { '?' };
+                format!("{}{}socket={}", self.connection_string, separator, encode_query(socket))
+            }
+            None => self.connection_string.clone(),
+        }
+    }
+
+    // Hosts of the primary in the order they are tried
+    pub fn hosts(&self) -> Vec<String> {
+        let mut urls = vec![self.connection_url()];
+        if let Some(failover) = &self.failover {
+            urls.extend(failover.connection_strings.iter().cloned());
+        }
+        urls
+    }
+
+    // Every pool the profile may run on, the primary first
+    pub fn pool_urls(&self) -> Vec<String> {
+        let mut urls = self.hosts();
+        if let Some(replicas) = &self.replicas {
+            urls.extend(replicas.connection_strings.iter().cloned());
+        }
+        urls
+    }
+}
+
+fn encode_query(value: &str) -> String {
+    value
+        .bytes()
+        .map(|b| match b {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'/' | b'.' | b'-' | b'_' | b'~' => {
+                (b as char).to_string()
+            }
+            _ => format!("%{:02X}", b),
+        })
+        .collect()
+}
+
+#[derive(Debug, Default, Clone, Copy, Deserialize)]
+#[serde(rename_all = "camelCase", deny_unknown_fields)]
+pub struct SlowQuery {
+    // Items taking at least this long are written to the slow query log, off when unset
+    pub threshold_ms: Option<u64>,
+    // Log the bound values along with the SQL. They can hold personal data, so off by default.
+    #[serde(default)]
+    pub log_values: bool,
+}
+
+#[derive(Debug, Default, Clone, Deserialize)]
+#[serde(rename_all = "camelCase", deny_unknown_fields)]
+pub struct Config {
+    #[serde(default)]
+    pub profiles: BTreeMap<String, Profile>,
+    #[serde(default)]
+    pub slow_query: SlowQuery,
+    // Statements are written to an audit log when set
+    pub audit: Option<AuditSettings>,
+    // Tunes the OTLP trace export, only used when built with the otel feature
+    pub telemetry: Option<TelemetrySettings>,
+    // Lets browser apps on other origins call the gateway
+    pub cors: Option<CorsSettings>,
+    // Settings listeners can pick with cors=NAME instead of cors
+    #[serde(default)]
+    pub cors_policies: BTreeMap<String, CorsSettings>,
+    // A directory served next to the API, e.g. a web front end
+    pub static_files: Option<StaticFilesSettings>,
+    // Serve the built-in web console at /console
+    #[serde(default)]
+    pub console: bool,
+    // Serve HTTPS instead of plain HTTP
+    pub tls: Option<TlsSettings>,
+    // Credentials for listeners that require authentication
+    pub auth: Option<AuthSettings>,
+    // Limits of the result cache used by items with cacheTtlSecs
+    #[serde(default)]
+    pub cache: CacheSettings,
+    // Principal to roles, for the table permissions of the REST routes
+    #[serde(default)]
+    pub roles: BTreeMap<String, Vec<String>>,
+}
+
+impl Config {
+    // Requests without a profile get the global settings
+    pub fn slow_query(&self, profile: Option<&Profile>) -> SlowQuery {
+        SlowQuery {
+            threshold_ms: profile
+                .and_then(|p| p.slow_query_ms)
+                .or(self.slow_query.threshold_ms),
+            log_values: self.slow_query.log_values,
+        }
+    }
+
+    pub fn validate(&self) -> Result<(), String> {
+        for (name, profile) in &self.profiles {
+            profile
+                .datetime
+                .resolve()
+                .map_err(|e| format!("profile '{}': {}", name, e))?;
+            if let Some(failover) = &profile.failover {
+                failover
+                    .validate()
+                    .map_err(|e| format!("profile '{}': {}", name, e))?;
+            }
+            if let Some(replicas) = &profile.replicas {
+                replicas
+                    .validate()
+                    .map_err(|e| format!("profile '{}': {}", name, e))?;
+            }
+            for (query_name, query) in &profile.queries {
+                query
+                    .validate()
+                    .map_err(|e| format!("profile '{}': query '{}': {}", name, query_name, e))?;
+            }
+        }
+        if let Some(cors) = &self.cors {
+            cors.validate()?;
+        }
+        for (name, cors) in &self.cors_policies {
+            cors.validate()
+                .map_err(|e| format!("corsPolicies '{}': {}", name, e))?;
+        }
+        if let Some(static_files) = &self.static_files {
+            static_files.validate()?;
+        }
+        if let Some(auth) = &self.auth {
+            auth.validate()?;
+        }
+        Ok(())
+    }
+}
+
+pub fn load_config(path: &str) -> Result<Config, String> {
+    let path = shellexpand::tilde(path).into_owned();
+    let text = fs::read_to_string(&path)
+        .map_err(|e| format!("could not read config file '{}': {}", path, e))?;
+    let config: Config = serde_yaml::from_str(&text)
+        .map_err(|e| format!("could not parse config file '{}': {}", path, e))?;
+    config.validate()?;
+    Ok(config)
+}
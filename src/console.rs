@@ -0,0 +1,49 @@
+// Original copyright notice
+// Copyright (c) 2023-, Germano Rizzo <oss /AT/ germanorizzo /DOT/ it>
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+// This work was inspired by sqliterg by Germano Rizzo and heavily modified to suite mysql.
+// Modifications c) 2023-, Nexon Asia Pacific also under apache 2.0 license
+
+use actix_web::{guard, http::header::ContentType, web, HttpResponse};
+use serde_json::json;
+
+use crate::statics::CONFIG;
+
+// Built into the binary, so the console needs no files on disk
+const INDEX: &str = include_str!("console/index.html");
+
+// The page only talks to the JSON API, so it gets the same auth as any other client.
+// Answers 404 while `enabled` returns false, so a config reload can turn it on and off.
+pub fn configure<F>(cfg: &mut web::ServiceConfig, enabled: F)
+where
+    F: Fn() -> bool + Clone + 'static,
+{
+    let on = |enabled: F| guard::fn_guard(move |_| enabled());
+    cfg.route("/console", web::get().guard(on(enabled.clone())).to(index))
+        .route("/console/profiles", web::get().guard(on(enabled)).to(profiles));
+}
+
+async fn index() -> HttpResponse {
+    HttpResponse::Ok()
+        .content_type(ContentType::html())
+        .body(INDEX)
+}
+
+// Names only, connection strings stay on the server
+async fn profiles() -> HttpResponse {
+    let config = CONFIG.read().unwrap().clone();
+    let names: Vec<&String> = config.profiles.keys().collect();
+    HttpResponse::Ok().json(json!({ "profiles": names }))
+}
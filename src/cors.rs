@@ -0,0 +1,249 @@
+// Original copyright notice
+// Copyright (c) 2023-, Germano Rizzo <oss /AT/ germanorizzo /DOT/ it>
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+// This work was inspired by sqliterg by Germano Rizzo and heavily modified to suite mysql.
+// Modifications c) 2023-, Nexon Asia Pacific also under apache 2.0 license
+
+use std::{
+    cell::RefCell,
+    future::{ready, Future, Ready},
+    pin::Pin,
+    rc::Rc,
+    task::{Context, Poll},
+};
+
+use actix_cors::{Cors, CorsMiddleware};
+use actix_web::{
+    body::{EitherBody, MessageBody},
+    dev::{Service, ServiceRequest, ServiceResponse, Transform},
+    http::{header::HeaderName, Method, Uri},
+    Error,
+};
+use serde::Deserialize;
+
+use crate::{config::Config, logging::REQUEST_ID_HEADER};
+
+const ANY: &str = "*";
+
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(rename_all = "camelCase", deny_unknown_fields)]
+pub struct CorsSettings {
+    // Full origins like https://app.example.com, or "*" for any
+    pub allowed_origins: Vec<String>,
+    #[serde(default = "default_methods")]
+    pub allowed_methods: Vec<String>,
+    #[serde(default = "default_headers")]
+    pub allowed_headers: Vec<String>,
+    // Lets the browser send cookies and authorization headers, can't be used with "*"
+    #[serde(default)]
+    pub allow_credentials: bool,
+    // How long browsers may cache a preflight answer
+    pub max_age_secs: Option<usize>,
+}
+
+fn default_methods() -> Vec<String> {
+    vec!["GET".to_string(), "POST".to_string()]
+}
+
+fn default_headers() -> Vec<String> {
+    ["content-type", "profile", REQUEST_ID_HEADER, "traceparent"]
+        .iter()
+        .map(|h| h.to_string())
+        .collect()
+}
+
+// Which settings a listener uses, the config's cors unless its --listen spec says otherwise
+#[derive(Debug, Clone, Default, PartialEq)]
+pub enum CorsPolicy {
+    #[default]
+    Global,
+    Off,
+    // One of corsPolicies
+    Named(String),
+}
+
+impl CorsPolicy {
+    // A named policy a reload removed turns CORS off on its listeners
+    pub fn settings(&self, config: &Config) -> Option<CorsSettings> {
+        match self {
+            CorsPolicy::Global => config.cors.clone(),
+            CorsPolicy::Off => None,
+            CorsPolicy::Named(name) => config.cors_policies.get(name).cloned(),
+        }
+    }
+}
+
+impl CorsSettings {
+    // actix-cors panics on bad values, so they are caught when the config is loaded
+    pub fn validate(&self) -> Result<(), String> {
+        if self.allowed_origins.is_empty() {
+            return Err("cors: allowedOrigins is empty".to_string());
+        }
+        for origin in &self.allowed_origins {
+            if origin == ANY {
+                if self.allow_credentials {
+                    return Err("cors: allowCredentials can't be used with origin '*'".to_string());
+                }
+                continue;
+            }
+            let valid = origin
+                .parse::<Uri>()
+                .map(|uri| {
+                    uri.scheme().is_some()
+                        && uri.host().is_some()
+                        && uri.path_and_query().is_none_or(|p| p.as_str() == "/")
+                        && !origin.ends_with('/')
+                })
+                .unwrap_or(false);
+            if !valid {
+                return Err(format!("cors: '{}' is not an origin like https://host[:port]", origin));
+            }
+        }
+        for method in &self.allowed_methods {
+            if method != ANY {
+                Method::from_bytes(method.as_bytes())
+                    .map_err(|_| format!("cors: '{}' is not an http method", method))?;
+            }
+        }
+        for header in &self.allowed_headers {
+            if header != ANY {
+                HeaderName::try_from(header.as_str())
+                    .map_err(|_| format!("cors: '{}' is not a header name", header))?;
+            }
+        }
+        Ok(())
+    }
+
+    fn build(&self) -> Cors {
+        let mut cors = Cors::default().expose_headers([REQUEST_ID_HEADER]);
+        for origin in &self.allowed_origins {
+            cors = if origin == ANY {
+                cors.allow_any_origin()
+            } else {
+                cors.allowed_origin(origin)
+            };
+        }
+        cors = if self.allowed_methods.iter().any(|m| m == ANY) {
+            cors.allow_any_method()
+        } else {
+            cors.allowed_methods(self.allowed_methods.iter().map(String::as_str))
+        };
+        cors = if self.allowed_headers.iter().any(|h| h == ANY) {
+            cors.allow_any_header()
+        } else {
+            cors.allowed_headers(self.allowed_headers.iter().map(String::as_str))
+        };
+        if self.allow_credentials {
+            cors = cors.supports_credentials();
+        }
+        cors.max_age(self.max_age_secs)
+    }
+}
+
+// CORS with the settings `current` returns, so a config reload applies to the next request.
+// Each worker keeps the Cors built for the settings it saw last.
+pub fn middleware<F>(current: F) -> Reloadable<F>
+where
+    F: Fn() -> Option<CorsSettings> + Clone + 'static,
+{
+    Reloadable(current)
+}
+
+pub struct Reloadable<F>(F);
+
+impl<S, B, F> Transform<S, ServiceRequest> for Reloadable<F>
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = Error> + 'static,
+    B: MessageBody + 'static,
+    F: Fn() -> Option<CorsSettings> + Clone + 'static,
+{
+    type Response = ServiceResponse<EitherBody<B>>;
+    type Error = Error;
+    type InitError = ();
+    type Transform = ReloadableMiddleware<S, F>;
+    type Future = Ready<Result<Self::Transform, Self::InitError>>;
+
+    fn new_transform(&self, service: S) -> Self::Future {
+        ready(Ok(ReloadableMiddleware {
+            inner: Rc::new(service),
+            current: self.0.clone(),
+            built: RefCell::new(None),
+        }))
+    }
+}
+
+type Built<S> = (Option<CorsSettings>, Option<CorsMiddleware<Shared<S>>>);
+
+pub struct ReloadableMiddleware<S, F> {
+    inner: Rc<S>,
+    current: F,
+    built: RefCell<Option<Built<S>>>,
+}
+
+impl<S, B, F> Service<ServiceRequest> for ReloadableMiddleware<S, F>
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = Error> + 'static,
+    B: MessageBody + 'static,
+    F: Fn() -> Option<CorsSettings>,
+{
+    type Response = ServiceResponse<EitherBody<B>>;
+    type Error = Error;
+    type Future = Pin<Box<dyn Future<Output = Result<Self::Response, Error>>>>;
+
+    fn poll_ready(&self, cx: &mut Context<'_>) -> Poll<Result<(), Error>> {
+        self.inner.poll_ready(cx)
+    }
+
+    fn call(&self, req: ServiceRequest) -> Self::Future {
+        let settings = (self.current)();
+        let mut built = self.built.borrow_mut();
+        if built.as_ref().is_none_or(|(seen, _)| *seen != settings) {
+            let cors = settings.as_ref().and_then(|settings| {
+                // Cors only fails on settings validate refuses
+                settings
+                    .build()
+                    .new_transform(Shared(self.inner.clone()))
+                    .into_inner()
+                    .inspect_err(|_| log::error!("Could not set up CORS, it is off"))
+                    .ok()
+            });
+            *built = Some((settings, cors));
+        }
+        match built.as_ref().and_then(|(_, cors)| cors.as_ref()) {
+            Some(cors) => cors.call(req),
+            None => {
+                let res = self.inner.call(req);
+                Box::pin(async move { res.await.map(ServiceResponse::map_into_left_body) })
+            }
+        }
+    }
+}
+
+// The inner service, shared by every Cors built around it
+pub struct Shared<S>(Rc<S>);
+
+impl<S: Service<ServiceRequest>> Service<ServiceRequest> for Shared<S> {
+    type Response = S::Response;
+    type Error = S::Error;
+    type Future = S::Future;
+
+    fn poll_ready(&self, cx: &mut Context<'_>) -> Poll<Result<(), S::Error>> {
+        self.0.poll_ready(cx)
+    }
+
+    fn call(&self, req: ServiceRequest) -> Self::Future {
+        self.0.call(req)
+    }
+}
@@ -0,0 +1,186 @@
+// Original copyright notice
+// Copyright (c) 2023-, Germano Rizzo <oss /AT/ germanorizzo /DOT/ it>
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+// This work was inspired by sqliterg by Germano Rizzo and heavily modified to suite mysql.
+// Modifications c) 2023-, Nexon Asia Pacific also under apache 2.0 license
+
+use std::str::FromStr;
+
+use chrono::{DateTime, FixedOffset, NaiveDateTime, Offset, TimeZone, Timelike};
+use chrono_tz::Tz;
+use serde::Deserialize;
+use serde_json::{json, Value};
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub enum DateTimeFormat {
+    // 2023-11-29 14:03:15
+    #[default]
+    Default,
+    // 2023-11-29T14:03:15+00:00
+    Iso8601,
+    // 2023-11-29T14:03:15Z
+    Rfc3339,
+    // Seconds since the unix epoch
+    Epoch,
+    // Milliseconds since the unix epoch
+    EpochMillis,
+}
+
+// Datetime settings as written in a profile or a request, every field is optional so
+// a request only needs to override what it cares about
+#[derive(Debug, Default, Clone, Deserialize)]
+#[serde(rename_all = "camelCase", deny_unknown_fields)]
+pub struct DateTimeSettings {
+    pub format: Option<DateTimeFormat>,
+    // Session time zone, either an offset like +02:00 or a name like Europe/Rome
+    pub time_zone: Option<String>,
+    // Fractional second digits, when absent only the digits that are present are shown
+    pub precision: Option<u8>,
+}
+
+impl DateTimeSettings {
+    // Fields set in overrides win over the ones in self
+    pub fn merge(&self, overrides: &DateTimeSettings) -> DateTimeSettings {
+        DateTimeSettings {
+            format: overrides.format.or(self.format),
+            time_zone: overrides.time_zone.clone().or_else(|| self.time_zone.clone()),
+            precision: overrides.precision.or(self.precision),
+        }
+    }
+
+    pub fn resolve(&self) -> Result<DateTimeOptions, String> {
+        if let Some(precision) = self.precision {
+            if precision > 9 {
+                return Err(format!("Datetime precision {} is above 9.", precision));
+            }
+        }
+        Ok(DateTimeOptions {
+            format: self.format.unwrap_or_default(),
+            zone: self.time_zone.as_deref().map(SessionZone::parse).transpose()?,
+            precision: self.precision,
+        })
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum SessionZone {
+    Fixed(FixedOffset),
+    Named(Tz),
+}
+
+impl SessionZone {
+    pub fn parse(text: &str) -> Result<SessionZone, String> {
+        let text = text.trim();
+        if text.eq_ignore_ascii_case("utc") || text == "Z" {
+            return Ok(SessionZone::Fixed(FixedOffset::east_opt(0).unwrap()));
+        }
+        if text.starts_with('+') || text.starts_with('-') {
+            return FixedOffset::from_str(text)
+                .map(SessionZone::Fixed)
+                .map_err(|_| format!("Invalid time zone offset '{}'.", text));
+        }
+        Tz::from_str(text)
+            .map(SessionZone::Named)
+            .map_err(|_| format!("Unknown time zone '{}'.", text))
+    }
+
+    // Value for SET time_zone, named zones need the time zone tables loaded on the server
+    pub fn sql_name(&self) -> String {
+        match self {
+            SessionZone::Fixed(offset) => offset.to_string(),
+            SessionZone::Named(tz) => tz.name().to_string(),
+        }
+    }
+
+    // Wall clock time in this zone for an absolute instant
+    pub fn to_local(&self, instant: &DateTime<FixedOffset>) -> NaiveDateTime {
+        match self {
+            SessionZone::Fixed(offset) => instant.with_timezone(offset).naive_local(),
+            SessionZone::Named(tz) => instant.with_timezone(tz).naive_local(),
+        }
+    }
+
+    // Attaches this zone's offset to a wall clock time. Times skipped by a DST change
+    // fall back to the standard offset.
+    pub fn localize(&self, naive: &NaiveDateTime) -> DateTime<FixedOffset> {
+        let offset = match self {
+            SessionZone::Fixed(offset) => *offset,
+            SessionZone::Named(tz) => tz
+                .from_local_datetime(naive)
+                .earliest()
+                .map(|d| d.offset().fix())
+                .unwrap_or_else(|| tz.offset_from_utc_datetime(naive).fix()),
+        };
+        DateTime::from_naive_utc_and_offset(*naive - offset, offset)
+    }
+}
+
+// Resolved settings used while reading and binding values
+#[derive(Debug, Default, Clone)]
+pub struct DateTimeOptions {
+    pub format: DateTimeFormat,
+    // None means the server default, and values are then taken to be utc
+    pub zone: Option<SessionZone>,
+    pub precision: Option<u8>,
+}
+
+impl DateTimeOptions {
+    pub fn to_local(&self, instant: &DateTime<FixedOffset>) -> NaiveDateTime {
+        match &self.zone {
+            Some(zone) => zone.to_local(instant),
+            None => instant.naive_utc(),
+        }
+    }
+
+    pub fn to_json(&self, naive: &NaiveDateTime) -> Value {
+        let localized = match &self.zone {
+            Some(zone) => zone.localize(naive),
+            None => naive.and_utc().fixed_offset(),
+        };
+        let fraction = fraction(naive.nanosecond(), self.precision);
+        match self.format {
+            DateTimeFormat::Default => {
+                json!(format!("{}{}", naive.format("%Y-%m-%d %H:%M:%S"), fraction))
+            }
+            DateTimeFormat::Iso8601 => json!(format!(
+                "{}{}{}",
+                naive.format("%Y-%m-%dT%H:%M:%S"),
+                fraction,
+                localized.format("%:z")
+            )),
+            DateTimeFormat::Rfc3339 => {
+                let offset = if localized.offset().local_minus_utc() == 0 {
+                    "Z".to_string()
+                } else {
+                    localized.format("%:z").to_string()
+                };
+                json!(format!("{}{}{}", naive.format("%Y-%m-%dT%H:%M:%S"), fraction, offset))
+            }
+            DateTimeFormat::Epoch => json!(localized.timestamp()),
+            DateTimeFormat::EpochMillis => json!(localized.timestamp_millis()),
+        }
+    }
+}
+
+fn fraction(nanos: u32, precision: Option<u8>) -> String {
+    let digits = format!("{:09}", nanos % 1_000_000_000);
+    match precision {
+        Some(0) => String::new(),
+        Some(p) => format!(".{}", &digits[..usize::from(p.min(9))]),
+        None if nanos == 0 => String::new(),
+        None => format!(".{}", digits.trim_end_matches('0')),
+    }
+}
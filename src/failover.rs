@@ -0,0 +1,284 @@
+// Original copyright notice
+// Copyright (c) 2023-, Germano Rizzo <oss /AT/ germanorizzo /DOT/ it>
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+// This work was inspired by sqliterg by Germano Rizzo and heavily modified to suite mysql.
+// Modifications c) 2023-, Nexon Asia Pacific also under apache 2.0 license
+
+use std::{
+    collections::{BTreeSet, HashMap},
+    sync::RwLock,
+    time::{Duration, Instant},
+};
+
+use actix_web::{
+    error,
+    rt::{self, time::sleep},
+};
+use lazy_static::lazy_static;
+use serde::Deserialize;
+use sqlx::{pool::PoolConnection, MySql};
+
+use crate::{
+    config::Profile,
+    logic::{acquire, get_pool},
+    metrics,
+    statics::CONFIG,
+};
+
+// How often hosts that are down are looked at, each is only probed once its backoff is over
+const PROBE_INTERVAL: Duration = Duration::from_secs(1);
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(rename_all = "camelCase", deny_unknown_fields)]
+pub struct FailoverSettings {
+    // Tried in order after connectionString
+    pub connection_strings: Vec<String>,
+    // Wait before probing a failed host again, doubling with every failed probe
+    #[serde(default = "default_backoff_secs")]
+    pub backoff_secs: u64,
+    #[serde(default = "default_max_backoff_secs")]
+    pub max_backoff_secs: u64,
+    // A host that takes longer to give a connection counts as down
+    #[serde(default = "default_connect_timeout_secs")]
+    pub connect_timeout_secs: u64,
+}
+
+fn default_backoff_secs() -> u64 {
+    1
+}
+
+fn default_max_backoff_secs() -> u64 {
+    60
+}
+
+fn default_connect_timeout_secs() -> u64 {
+    2
+}
+
+impl Default for FailoverSettings {
+    fn default() -> Self {
+        FailoverSettings {
+            connection_strings: vec![],
+            backoff_secs: default_backoff_secs(),
+            max_backoff_secs: default_max_backoff_secs(),
+            connect_timeout_secs: default_connect_timeout_secs(),
+        }
+    }
+}
+
+impl FailoverSettings {
+    pub fn validate(&self) -> Result<(), String> {
+        if self.connection_strings.is_empty() {
+            return Err("failover: connectionStrings is empty".to_string());
+        }
+        if self.connect_timeout_secs == 0 {
+            return Err("failover: connectTimeoutSecs must be at least 1".to_string());
+        }
+        if self.backoff_secs == 0 || self.max_backoff_secs < self.backoff_secs {
+            return Err(
+                "failover: backoffSecs must be at least 1 and at most maxBackoffSecs".to_string(),
+            );
+        }
+        Ok(())
+    }
+
+    pub fn connect_timeout(&self) -> Duration {
+        Duration::from_secs(self.connect_timeout_secs)
+    }
+
+    // Wait after the given number of failures in a row
+    pub fn backoff(&self, failures: u32) -> Duration {
+        let secs = self
+            .backoff_secs
+            .saturating_mul(1 << failures.saturating_sub(1).min(32))
+            .min(self.max_backoff_secs);
+        Duration::from_secs(secs)
+    }
+}
+
+struct Health {
+    failures: u32,
+    retry_at: Instant,
+    profile: String,
+    // "host 2" or "replica 1", connection strings hold passwords and are never logged
+    label: String,
+    settings: FailoverSettings,
+}
+
+lazy_static! {
+    // Hosts that are down by connection string, until a probe gets a connection again
+    static ref DOWN: RwLock<HashMap<String, Health>> = RwLock::new(HashMap::new());
+}
+
+pub fn is_down(url: &str) -> bool {
+    DOWN.read().unwrap().contains_key(url)
+}
+
+pub fn mark_down(
+    url: &str,
+    profile_name: &str,
+    label: &str,
+    settings: &FailoverSettings,
+    error: &str,
+) {
+    let mut down = DOWN.write().unwrap();
+    let health = down.entry(url.to_string()).or_insert_with(|| Health {
+        failures: 0,
+        retry_at: Instant::now(),
+        profile: profile_name.to_string(),
+        label: label.to_string(),
+        settings: settings.clone(),
+    });
+    health.failures += 1;
+    let backoff = settings.backoff(health.failures);
+    health.retry_at = Instant::now() + backoff;
+    log::warn!(
+        profile = profile_name;
+        "{} is down, probing again in {}s: {}", label, backoff.as_secs(), error
+    );
+}
+
+pub fn mark_up(url: &str) {
+    if let Some(health) = DOWN.write().unwrap().remove(url) {
+        log::info!(profile = health.profile.as_str(); "{} is back", health.label);
+    }
+}
+
+// Where a connection came from, so losing it mid request can mark the host down
+#[derive(Debug, Clone)]
+pub struct Host {
+    url: String,
+    profile: String,
+    label: String,
+    settings: FailoverSettings,
+}
+
+impl Host {
+    pub fn new(url: &str, profile_name: &str, label: String, settings: &FailoverSettings) -> Host {
+        Host {
+            url: url.to_string(),
+            profile: profile_name.to_string(),
+            label,
+            settings: settings.clone(),
+        }
+    }
+
+    // The request that saw it fail is not retried, the next one goes elsewhere
+    pub fn lost(&self, error: &str) {
+        mark_down(&self.url, &self.profile, &self.label, &self.settings, error);
+    }
+}
+
+// Getting a connection runs nothing on the server, so trying the next host is always safe
+pub async fn connect(
+    url: &str,
+    profile_name: &str,
+    settings: &FailoverSettings,
+) -> Result<PoolConnection<MySql>, actix_web::Error> {
+    let timeout = settings.connect_timeout();
+    let connect = async {
+        let pool = get_pool(url).await?;
+        acquire(&pool, profile_name).await
+    };
+    rt::time::timeout(timeout, connect)
+        .await
+        .unwrap_or_else(|_| {
+            metrics::observe_error(profile_name, "connection");
+            Err(error::ErrorServiceUnavailable(format!(
+                "no connection within {}s",
+                timeout.as_secs()
+            )))
+        })
+}
+
+// A connection to the first host of the profile that is up. Hosts that are down are only
+// tried once every other one failed.
+pub async fn primary(
+    profile: &Profile,
+    profile_name: &str,
+) -> Result<PoolConnection<MySql>, actix_web::Error> {
+    primary_host(profile, profile_name)
+        .await
+        .map(|(conn, _)| conn)
+}
+
+// The same, along with the host it came from when the profile fails over
+pub async fn primary_host(
+    profile: &Profile,
+    profile_name: &str,
+) -> Result<(PoolConnection<MySql>, Option<Host>), actix_web::Error> {
+    let Some(settings) = &profile.failover else {
+        let pool = get_pool(&profile.connection_url())
+            .await
+            .inspect_err(|_| metrics::observe_error(profile_name, "connection"))?;
+        return Ok((acquire(&pool, profile_name).await?, None));
+    };
+    let hosts = profile.hosts();
+    let (up, down): (Vec<_>, Vec<_>) = hosts.iter().enumerate().partition(|(_, url)| !is_down(url));
+    let mut last_error = None;
+    for (index, url) in up.into_iter().chain(down) {
+        let host = Host::new(url, profile_name, format!("host {}", index + 1), settings);
+        match connect(url, profile_name, settings).await {
+            Ok(conn) => {
+                mark_up(url);
+                return Ok((conn, Some(host)));
+            }
+            Err(e) => {
+                host.lost(&e.to_string());
+                last_error = Some(e);
+            }
+        }
+    }
+    Err(last_error.expect("a profile has at least one host"))
+}
+
+// Probes hosts that are down once their backoff is over, so profiles fail back to the
+// hosts listed first. Runs until the process ends.
+pub fn probe() {
+    rt::spawn(async {
+        loop {
+            sleep(PROBE_INTERVAL).await;
+            // Hosts no profile uses any more since a reload are forgotten, not reconnected
+            let urls: BTreeSet<String> = CONFIG
+                .read()
+                .unwrap()
+                .profiles
+                .values()
+                .flat_map(|p| p.pool_urls())
+                .collect();
+            let due: Vec<_> = {
+                let mut down = DOWN.write().unwrap();
+                down.retain(|url, _| urls.contains(url));
+                down.iter()
+                    .filter(|(_, health)| health.retry_at <= Instant::now())
+                    .map(|(url, health)| {
+                        (
+                            url.clone(),
+                            health.profile.clone(),
+                            health.label.clone(),
+                            health.settings.clone(),
+                        )
+                    })
+                    .collect()
+            };
+            for (url, profile, label, settings) in due {
+                match connect(&url, &profile, &settings).await {
+                    Ok(_) => mark_up(&url),
+                    Err(e) => mark_down(&url, &profile, &label, &settings, &e.to_string()),
+                }
+            }
+        }
+    });
+}
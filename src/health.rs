@@ -0,0 +1,104 @@
+// Original copyright notice
+// Copyright (c) 2023-, Germano Rizzo <oss /AT/ germanorizzo /DOT/ it>
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+// This work was inspired by sqliterg by Germano Rizzo and heavily modified to suite mysql.
+// Modifications c) 2023-, Nexon Asia Pacific also under apache 2.0 license
+
+use std::time::{Duration, Instant};
+
+use actix_web::{rt, HttpResponse};
+use serde_json::{json, Map, Value};
+
+use crate::{config::Profile, failover, statics::CONFIG};
+
+// How long a profile gets to answer SELECT 1, including connecting if needed
+const READY_TIMEOUT: Duration = Duration::from_secs(5);
+
+// Liveness, answers as long as the process serves requests
+pub async fn healthz() -> HttpResponse {
+    HttpResponse::Ok().json(json!({"status": "ok"}))
+}
+
+// Readiness, runs SELECT 1 through the pool of every configured profile, on whichever of
+// its hosts is up. Fails when any required profile is down.
+pub async fn readyz() -> HttpResponse {
+    let config = CONFIG.read().unwrap().clone();
+    let checks: Vec<_> = config
+        .profiles
+        .iter()
+        .map(|(name, profile)| {
+            let (name, profile) = (name.clone(), profile.clone());
+            (
+                name.clone(),
+                profile.required,
+                rt::spawn(async move { check(&profile, &name).await }),
+            )
+        })
+        .collect();
+
+    let mut ready = true;
+    let mut profiles = Map::new();
+    for (name, required, check) in checks {
+        let outcome = check
+            .await
+            .unwrap_or_else(|e| Err(format!("check failed: {}", e)));
+        let mut status = Map::new();
+        status.insert("required".to_string(), json!(required));
+        match outcome {
+            Ok(latency) => {
+                status.insert("status".to_string(), json!("up"));
+                status.insert(
+                    "latencyMs".to_string(),
+                    json!((latency.as_secs_f64() * 1_000_000.0).round() / 1000.0),
+                );
+            }
+            Err(e) => {
+                ready &= !required;
+                status.insert("status".to_string(), json!("down"));
+                status.insert("error".to_string(), json!(e));
+            }
+        }
+        profiles.insert(name, Value::Object(status));
+    }
+
+    let body = json!({
+        "status": if ready { "ready" } else { "unavailable" },
+        "profiles": profiles,
+    });
+    if ready {
+        HttpResponse::Ok().json(body)
+    } else {
+        HttpResponse::ServiceUnavailable().json(body)
+    }
+}
+
+async fn check(profile: &Profile, profile_name: &str) -> Result<Duration, String> {
+    let start = Instant::now();
+    let ping = async {
+        let mut conn = failover::primary(profile, profile_name)
+            .await
+            .map_err(|e| e.to_string())?;
+        sqlx::query("SELECT 1")
+            .execute(&mut *conn)
+            .await
+            .map_err(|e| e.to_string())?;
+        Ok(start.elapsed())
+    };
+    // Every host gets its turn before the profile counts as down
+    let timeout = READY_TIMEOUT * profile.hosts().len() as u32;
+    rt::time::timeout(timeout, ping)
+        .await
+        .unwrap_or_else(|_| Err(format!("no answer within {}s", timeout.as_secs())))
+}
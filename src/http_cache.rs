@@ -0,0 +1,61 @@
+// Original copyright notice
+// Copyright (c) 2023-, Germano Rizzo <oss /AT/ germanorizzo /DOT/ it>
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+// This work was inspired by sqliterg by Germano Rizzo and heavily modified to suite mysql.
+// Modifications c) 2023-, Nexon Asia Pacific also under apache 2.0 license
+
+use actix_web::{
+    http::header::{self, HeaderValue},
+    HttpRequest, HttpResponse,
+};
+use ring::digest;
+
+// Strong validator over the body, so equal bodies always get equal tags
+pub fn etag(body: &[u8]) -> String {
+    format!("\"{}\"", hex::encode(digest::digest(&digest::SHA256, body)))
+}
+
+// If-None-Match holds a list of tags or "*", weak ones compare like strong ones for GET
+pub fn not_modified(req: &HttpRequest, etag: &str) -> bool {
+    req.headers()
+        .get_all(header::IF_NONE_MATCH)
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .map(|tag| tag.trim())
+        .any(|tag| tag == "*" || tag.strip_prefix("W/").unwrap_or(tag) == etag)
+}
+
+// 304 when the client already has this body, otherwise the body with its tag. Without
+// a max age clients revalidate every time, as only the server knows when data changed.
+pub fn respond(req: &HttpRequest, body: Vec<u8>, max_age_secs: Option<u64>) -> HttpResponse {
+    let etag = etag(&body);
+    let mut res = if not_modified(req, &etag) {
+        HttpResponse::NotModified().finish()
+    } else {
+        HttpResponse::Ok().content_type("application/json").body(body)
+    };
+    let headers = res.headers_mut();
+    if let Ok(value) = HeaderValue::from_str(&etag) {
+        headers.insert(header::ETAG, value);
+    }
+    let cache_control = match max_age_secs {
+        Some(secs) => format!("max-age={}", secs),
+        None => "no-cache".to_string(),
+    };
+    if let Ok(value) = HeaderValue::from_str(&cache_control) {
+        headers.insert(header::CACHE_CONTROL, value);
+    }
+    res
+}
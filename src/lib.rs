@@ -17,29 +17,173 @@
 // Modifications c) 2023-, Nexon Asia Pacific also under apache 2.0 license
 
 pub mod statics;
+use std::{sync::Arc, time::Duration};
+
 use actix_web::{
-    web,
-    App, HttpServer,  middleware::Logger
+    rt, web,
+    App, HttpServer
 };
+pub mod audit;
+pub mod auth;
+pub mod bulk;
+pub mod cache;
 pub mod commandline;
+pub mod config;
+pub mod console;
+pub mod cors;
+pub mod datetime;
+pub mod failover;
+pub mod health;
+pub mod http_cache;
+pub mod logging;
 pub mod logic;
+pub mod metrics;
+pub mod params;
+pub mod reload;
+pub mod replicas;
 pub mod req_res;
-use crate::commandline::parse_cli;
+pub mod rest;
+pub mod schema;
+pub mod shutdown;
+pub mod static_files;
+pub mod stored_queries;
+pub mod telemetry;
+pub mod tls;
+#[cfg(unix)]
+pub mod unix_socket;
+use crate::{
+    auth::AuthMode,
+    commandline::{parse_cli, ListenAddress, Routes},
+};
 
 #[actix_web::main]
 pub async fn main() -> std::io::Result<()> {
     let cli = parse_cli();
     //let mut connection_pools: Mutex<HashMap<String, Pool<MySql>>> = Mutex::new(HashMap::new());
     lazy_static::initialize(&statics::CONNECTION_WATER_PARK);
-    env_logger::init_from_env(env_logger::Env::new().default_filter_or("info"));
-    let bind_addr = format!("{}:{}", cli.bind_host, cli.port);
-    println!("sqlxrg - Listening on {}", &bind_addr);
-    HttpServer::new(|| {
-        App::new()
-            .wrap(Logger::default())
-            .route("/", web::post().to(logic::handler))
-    })
-    .bind(bind_addr)?
-    .run()
-    .await
+    logging::init(cli.log_format);
+    if let Some(path) = &cli.config {
+        let config = config::load_config(path)
+            .map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidInput, e))?;
+        log::info!("Loaded {} profile(s) from {}", config.profiles.len(), path);
+        if let Some(audit) = &config.audit {
+            audit::init(Some(audit))?;
+            log::info!("Writing audit log to {}", audit.path);
+        }
+        *statics::CONFIG.write().unwrap() = Arc::new(config);
+    }
+    telemetry::init(statics::CONFIG.read().unwrap().telemetry.as_ref())
+        .map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidInput, e))?;
+    let config = statics::CONFIG.read().unwrap().clone();
+    let listeners = cli.listeners(config.tls.is_some());
+    for listener in &listeners {
+        listener
+            .check(&config)
+            .map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidInput, e))?;
+    }
+    // One certificate for every https listener, reloaded together
+    let certs = match &config.tls {
+        Some(settings) if listeners.iter().any(|l| matches!(l.address, ListenAddress::Tcp { tls: true, .. })) => {
+            Some(tls::resolver(settings).map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidInput, e))?)
+        }
+        _ => None,
+    };
+    reload::init(cli.config.clone(), certs.clone());
+    reload::reload_on_sighup()?;
+    replicas::monitor();
+    failover::probe();
+    let client_auth = config.tls.as_ref().and_then(|tls| tls.client_auth.clone());
+    let mut servers = vec![];
+    for listener in listeners {
+        let routes = listener.routes;
+        let auth = listener.auth;
+        let cors_policy = listener.cors.clone();
+        let client_auth = client_auth.clone();
+        let server = HttpServer::new(move || {
+            let config = statics::CONFIG.read().unwrap().clone();
+            let cors_policy = cors_policy.clone();
+            App::new()
+                .wrap_fn(shutdown::middleware)
+                .wrap_fn(move |req, srv| auth::middleware(auth, req, srv))
+                .wrap(cors::middleware(move || cors_policy.settings(&statics::CONFIG.read().unwrap())))
+                .wrap_fn(metrics::middleware)
+                .wrap_fn(logging::middleware)
+                .configure(|cfg| configure_routes(cfg, routes, &config))
+        })
+        .on_connect(move |conn, ext| {
+            if let Some(client_auth) = &client_auth {
+                tls::on_connect(conn, ext, client_auth);
+            }
+        })
+        .disable_signals()
+        .shutdown_timeout(shutdown::ROLLBACK_GRACE.as_secs());
+        let server = match &listener.address {
+            ListenAddress::Tcp { addr, tls: true } => {
+                let (settings, certs) = config.tls.as_ref().zip(certs.as_ref()).expect("checked with the listeners");
+                let tls_config = tls::server_config(settings, certs, auth == AuthMode::Mtls)
+                    .map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidInput, e))?;
+                server.bind_rustls_0_23(addr, tls_config)?
+            }
+            ListenAddress::Tcp { addr, tls: false } => server.bind(addr)?,
+            // Plain HTTP only, whoever can open the socket is trusted as much as a local proxy
+            #[cfg(unix)]
+            ListenAddress::Unix(path) => {
+                let listener = unix_socket::bind(path, cli.unix_socket_mode, cli.unix_socket_owner)?;
+                server.listen_uds(listener)?
+            }
+            #[cfg(not(unix))]
+            ListenAddress::Unix(path) => {
+                return Err(std::io::Error::new(
+                    std::io::ErrorKind::Unsupported,
+                    format!("can't listen on {}, unix sockets are not supported here", path.display()),
+                ))
+            }
+        };
+        log::info!("Listening on {}", listener);
+        servers.push(server.run());
+    }
+    shutdown::drain_on_signal(
+        servers.iter().map(|server| server.handle()).collect(),
+        Duration::from_secs(cli.drain_timeout),
+    )?;
+    let servers: Vec<_> = servers.into_iter().map(rt::spawn).collect();
+    // Every server is stopped by the drain, so waiting on them in turn is enough
+    let mut result = Ok(());
+    for server in servers {
+        let outcome = server
+            .await
+            .map_err(std::io::Error::other)
+            .and_then(|r| r);
+        if result.is_ok() {
+            result = outcome;
+        }
+    }
+    telemetry::shutdown();
+    result
+}
+
+fn configure_routes(cfg: &mut web::ServiceConfig, routes: Routes, config: &config::Config) {
+    if routes.api {
+        cfg.route("/", web::post().to(logic::handler))
+            .route("/db/{profile}/schema", web::get().to(schema::handler))
+            .route("/db/{profile}/q/{name}", web::get().to(stored_queries::handler))
+            .configure(rest::configure);
+    }
+    if routes.health {
+        cfg.route("/healthz", web::get().to(health::healthz))
+            .route("/readyz", web::get().to(health::readyz));
+    }
+    if routes.metrics {
+        cfg.route("/metrics", web::get().to(metrics::handler));
+    }
+    if routes.admin {
+        cfg.route("/admin/reload", web::post().to(reload::handler))
+            .route("/admin/cache/invalidate", web::post().to(cache::handler));
+    }
+    if routes.console {
+        console::configure(cfg, || statics::CONFIG.read().unwrap().console);
+    }
+    if routes.static_files {
+        static_files::configure(cfg, config.static_files.as_ref());
+    }
 }
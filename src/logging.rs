@@ -0,0 +1,154 @@
+// Original copyright notice
+// Copyright (c) 2023-, Germano Rizzo <oss /AT/ germanorizzo /DOT/ it>
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+// This work was inspired by sqliterg by Germano Rizzo and heavily modified to suite mysql.
+// Modifications c) 2023-, Nexon Asia Pacific also under apache 2.0 license
+
+use std::{future::Future, io::Write, time::Instant};
+
+use actix_web::{
+    dev::{Service, ServiceRequest, ServiceResponse},
+    http::header::{HeaderMap, HeaderName, HeaderValue},
+    HttpMessage,
+};
+use chrono::{SecondsFormat, Utc};
+use clap::ValueEnum;
+use log::kv::{Error, Key, Value as KvValue, VisitSource};
+use ring::rand::{SecureRandom, SystemRandom};
+use serde_json::{json, Map, Value};
+
+pub const REQUEST_ID_HEADER: &str = "x-request-id";
+
+// Longest request id accepted from a client, anything else gets replaced
+const MAX_REQUEST_ID_LEN: usize = 128;
+
+#[derive(Debug, Clone, Copy, PartialEq, ValueEnum)]
+pub enum LogFormat {
+    Text,
+    Json,
+}
+
+// Stored in the request extensions by the middleware in lib::main
+#[derive(Debug, Clone)]
+pub struct RequestId(pub String);
+
+pub fn init(format: LogFormat) {
+    let mut builder =
+        env_logger::Builder::from_env(env_logger::Env::new().default_filter_or("info"));
+    match format {
+        LogFormat::Text => builder.format(|buf, record| {
+            let mut fields = KvCollector(Map::new());
+            let _ = record.key_values().visit(&mut fields);
+            let mut line = format!(
+                "[{} {:<5} {}] {}",
+                Utc::now().to_rfc3339_opts(SecondsFormat::Millis, true),
+                record.level(),
+                record.target(),
+                record.args()
+            );
+            for (key, value) in fields.0 {
+                match value {
+                    Value::String(s) => line.push_str(&format!(" {}={}", key, s)),
+                    other => line.push_str(&format!(" {}={}", key, other)),
+                }
+            }
+            writeln!(buf, "{}", line)
+        }),
+        LogFormat::Json => builder.format(|buf, record| {
+            let mut fields = KvCollector(Map::new());
+            fields.0.insert(
+                "timestamp".to_string(),
+                json!(Utc::now().to_rfc3339_opts(SecondsFormat::Millis, true)),
+            );
+            fields.0.insert("level".to_string(), json!(record.level().as_str()));
+            fields.0.insert("target".to_string(), json!(record.target()));
+            fields.0.insert("message".to_string(), json!(record.args().to_string()));
+            let _ = record.key_values().visit(&mut fields);
+            writeln!(buf, "{}", Value::Object(fields.0))
+        }),
+    };
+    builder.init();
+}
+
+struct KvCollector(Map<String, Value>);
+
+impl<'kvs> VisitSource<'kvs> for KvCollector {
+    fn visit_pair(&mut self, key: Key<'kvs>, value: KvValue<'kvs>) -> Result<(), Error> {
+        let value = if let Some(x) = value.to_u64() {
+            json!(x)
+        } else if let Some(x) = value.to_i64() {
+            json!(x)
+        } else if let Some(x) = value.to_f64() {
+            json!(x)
+        } else if let Some(x) = value.to_bool() {
+            json!(x)
+        } else {
+            json!(value.to_string())
+        };
+        self.0.insert(key.as_str().to_string(), value);
+        Ok(())
+    }
+}
+
+// The client's X-Request-Id when it is sensible, otherwise a new random one
+pub fn request_id(headers: &HeaderMap) -> String {
+    if let Some(id) = headers.get(REQUEST_ID_HEADER).and_then(|h| h.to_str().ok()) {
+        if !id.is_empty()
+            && id.len() <= MAX_REQUEST_ID_LEN
+            && id.bytes().all(|b| b.is_ascii_graphic())
+        {
+            return id.to_string();
+        }
+    }
+    let mut bytes = [0u8; 16];
+    SystemRandom::new()
+        .fill(&mut bytes)
+        .expect("system random source failed");
+    hex::encode(bytes)
+}
+
+// Tags the request with its id, echoes it back in the response and writes the access log
+pub fn middleware<S, B>(
+    req: ServiceRequest,
+    srv: &S,
+) -> impl Future<Output = Result<ServiceResponse<B>, actix_web::Error>>
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = actix_web::Error>,
+{
+    let start = Instant::now();
+    let request_id = request_id(req.headers());
+    req.extensions_mut().insert(RequestId(request_id.clone()));
+    let method = req.method().to_string();
+    let path = req.path().to_string();
+    let peer = req.peer_addr().map(|a| a.ip().to_string()).unwrap_or_default();
+    let fut = srv.call(req);
+    async move {
+        let mut res = fut.await?;
+        if let Ok(value) = HeaderValue::from_str(&request_id) {
+            res.headers_mut()
+                .insert(HeaderName::from_static(REQUEST_ID_HEADER), value);
+        }
+        let status = res.status().as_u16();
+        log::info!(
+            target: "sqlxrg::access",
+            request_id = request_id.as_str(),
+            peer = peer.as_str(),
+            status = status,
+            elapsed_ms = start.elapsed().as_millis() as u64;
+            "{} {} {}", method, path, status
+        );
+        Ok(res)
+    }
+}
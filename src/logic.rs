@@ -16,76 +16,76 @@
 // This work was inspired by sqliterg by Germano Rizzo and heavily modified to suite mysql.
 // Modifications c) 2023-, Nexon Asia Pacific also under apache 2.0 license
 
-use actix_web::{error, web, HttpRequest};
+use actix_web::{error, web, HttpMessage, HttpRequest};
 use serde_json::{json, Map, Value};
 use sqlx::{
-    mysql::{MySqlConnectOptions, MySqlTypeInfo}, types::chrono::{self, DateTime}, Column, Decode, Execute, MySql, MySqlPool, Pool, Row, Transaction, TypeInfo, ValueRef
+    error::ErrorKind, mysql::MySqlConnectOptions, pool::PoolConnection, types::chrono, Column, Connection, Decode, MySql, MySqlPool, Pool, Row, Transaction, TypeInfo, ValueRef
+};
+use std::{
+    result::Result,
+    time::{Duration, Instant},
 };
-use std::result::Result;
 
 use crate::{
-    req_res::{self, Response, ResponseEnum, ResponseItem},
-    statics::CONNECTION_WATER_PARK,
+    audit::{self, AuditEntry},
+    bulk::{BulkMode, InsertTemplate},
+    cache,
+    config::{Config, Profile, SlowQuery},
+    failover::Host,
+    logging::{self, RequestId},
+    metrics,
+    params::{bind_values, expand_lists, BindOptions},
+    req_res::{self, ReqTransactionItem, Response, ResponseEnum, ResponseItem},
+    replicas,
+    shutdown,
+    statics::{CONFIG, CONNECTION_WATER_PARK},
+    telemetry,
 };
 
-#[allow(clippy::type_complexity)]
 async fn do_query(
     tx: &mut Transaction<'_, MySql>,
     sql: &str,
     tag: &Option<String>,
     values: &Option<Value>,
+    bind_opts: &BindOptions,
 ) -> ResponseEnum {
-    let mut qry = sqlx::query(sql);
-    let mut arr = vec![];
-    println!("Tmp debug called do query");
-    match values {
-        Some(p) => match p {
-            Value::Array(x) => {
-                for str in x {
-                    arr.push(str);
-                }
-                //arr.push(s.as_str());
-            }
-            _ => {
-                return ResponseEnum::Error {
-                    tag: tag.clone(),
-                    error: Box::from("Values must be array."),
-                };
-            }
-        },
-        None => {}
-    }
+    let span = telemetry::Span::child("do_query");
+    span.statement(sql);
+    let result = run_query(tx, sql, tag, values, bind_opts).await;
+    span.results(std::slice::from_ref(&result));
+    result
+}
 
-    for x in arr {
-        match x {
-            Value::Null => {
-                qry = qry.bind(Option::<String>::None)
-            },
-            Value::String(s) => {
-                qry = qry.bind(s);
-            }
-            Value::Bool(b) => {
-                qry = qry.bind(b);
-            }
-            Value::Number(n) => {
-                if let Some(x) = n.as_u64() {
-                    qry = qry.bind(x);
-                } else if let Some(x) = n.as_i64() {
-                    qry = qry.bind(x);
-                } else if let Some(x) = n.as_f64() {
-                    qry = qry.bind(x);
-                } else {
-                    return ResponseEnum::Error {
-                        tag: tag.clone(),
-                        error: Box::from("Could not deserialise number field in values."),
-                    };
-                }
-            }
-            _ => {
-                return ResponseEnum::Error{tag: tag.clone(), error:Box::from("Json value varient exhausted, please make sure all values are either a string, number, or boolean.")};
-            }
+#[allow(clippy::type_complexity)]
+async fn run_query(
+    tx: &mut Transaction<'_, MySql>,
+    sql: &str,
+    tag: &Option<String>,
+    values: &Option<Value>,
+    bind_opts: &BindOptions,
+) -> ResponseEnum {
+    let expanded = match expand_lists(sql, values, bind_opts) {
+        Ok(expanded) => expanded,
+        Err(e) => {
+            return ResponseEnum::Error {
+                tag: tag.clone(),
+                error: Box::from(e),
+            };
         }
-    }
+    };
+    let (sql, values) = match &expanded {
+        Some((sql, values)) => (sql.as_str(), values),
+        None => (sql, values),
+    };
+    let qry = match bind_values(sqlx::query(sql), values, bind_opts) {
+        Ok(qry) => qry,
+        Err(e) => {
+            return ResponseEnum::Error {
+                tag: tag.clone(),
+                error: Box::from(e),
+            };
+        }
+    };
 
     let results = match qry.fetch_all(&mut **tx).await {
         Ok(results) => results,
@@ -99,18 +99,9 @@ async fn do_query(
 
     let mut all_rows = vec![];
     for row in results {
-        println!("Tmp debug row: {:?}", row);
-
         let cols = row.columns();
         let mut map = Map::<String, Value>::new();
         for col in cols {
-        println!("Tmp debug col: {:?}", col);
-            let type_info: &MySqlTypeInfo = col.type_info();
-            if type_info.is_null() {
-                //May need to do something here
-                println!("Value: NULL");
-            }
-
             //https://github.com/launchbadge/sqlx/issues/182
             let raw_value = match row.try_get_raw(col.ordinal()) {
                 Ok(raw_value) => raw_value,
@@ -121,7 +112,6 @@ async fn do_query(
                     };
                 }
             };
-            println!("raw_value.type_info().name() = {:?}", raw_value.type_info().name());
 
             match raw_value.type_info().name() {
                 "FLOAT" | "FLOAT4" | "FLOAT8" => match <f32 as Decode<sqlx::mysql::MySql>>::decode(raw_value) {
@@ -233,17 +223,13 @@ async fn do_query(
                     }
                 }
                 
-                "DATETIME" | "DATETIME2" | "DATETIMEOFFSET" | "TIMESTAMP" | "TIMESTAMPTZ" => {
-                    let date_time =
-                        <chrono::NaiveDateTime as Decode<sqlx::mysql::MySql>>::decode(raw_value)
-                            .map(|d| d.and_utc());
-
-                    map.insert(
-                        col.name().to_string(),
-                        Value::String(date_time.as_ref().map_or_else(ToString::to_string, |x| {
-                            DateTime::format(x, "%Y-%m-%d %H:%M:%S").to_string()
-                        })),
-                    );
+                "DATETIME" | "DATETIME2" | "DATETIMEOFFSET" | "TIMESTAMP" | "TIMESTAMPTZ" => match <chrono::NaiveDateTime as Decode<sqlx::mysql::MySql>>::decode(raw_value) {
+                    Ok(val) => {
+                        map.insert(col.name().to_string(), bind_opts.datetime.to_json(&val));
+                    },
+                    Err(_) => {
+                        map.insert(col.name().to_string(), json!(null));
+                    }
                 }
                 "JSON" | "JSON[]" | "JSONB" | "JSONB[]" => match <Value as Decode<sqlx::mysql::MySql>>::decode(raw_value) {
                     Ok(val) => {
@@ -281,59 +267,44 @@ async fn do_single_statement(
     sql: &str,
     tag: &Option<String>,
     values: Option<Value>,
+    bind_opts: &BindOptions,
 ) -> ResponseEnum {
-    let mut stmt = sqlx::query(sql);
-    let mut arr: Vec<Value> = vec![];
-
-    match values {
-        Some(p) => match p {
-            Value::Array(x) => {
-                for str in x {
-                    arr.push(str);
-                }
-                //arr.push(s.as_str());
-            }
-            _ => {
-                return ResponseEnum::Error {
-                    tag: tag.clone(),
-                    error: Box::from("Values must be array."),
-                };
-            }
-        },
-        None => {}
-    }
+    let span = telemetry::Span::child("do_single_statement");
+    span.statement(sql);
+    let result = run_single_statement(tx, sql, tag, values, bind_opts).await;
+    span.results(std::slice::from_ref(&result));
+    result
+}
 
-    for x in arr {
-        match x {
-            Value::Null => {
-                
-                stmt = stmt.bind(Option::<String>::None)
-            },
-            Value::String(s) => {
-                stmt = stmt.bind(s);
-            }
-            Value::Bool(b) => {
-                stmt = stmt.bind(b);
-            }
-            Value::Number(n) => {
-                if let Some(x) = n.as_u64() {
-                    stmt = stmt.bind(x);
-                } else if let Some(x) = n.as_i64() {
-                    stmt = stmt.bind(x);
-                } else if let Some(x) = n.as_f64() {
-                    stmt = stmt.bind(x);
-                } else {
-                    return ResponseEnum::Error {
-                        tag: tag.clone(),
-                        error: Box::from("Could not deserialise number field in values."),
-                    };
-                }
-            }
-            _ => {
-                return ResponseEnum::Error{tag: tag.clone(), error:Box::from("Json value varient exhausted, please make sure all values are either a string, number, or boolean.")};
-            }
+async fn run_single_statement(
+    tx: &mut Transaction<'_, MySql>,
+    sql: &str,
+    tag: &Option<String>,
+    values: Option<Value>,
+    bind_opts: &BindOptions,
+) -> ResponseEnum {
+    let expanded = match expand_lists(sql, &values, bind_opts) {
+        Ok(expanded) => expanded,
+        Err(e) => {
+            return ResponseEnum::Error {
+                tag: tag.clone(),
+                error: Box::from(e),
+            };
         }
-    }
+    };
+    let (sql, values) = match &expanded {
+        Some((sql, values)) => (sql.as_str(), values),
+        None => (sql, &values),
+    };
+    let stmt = match bind_values(sqlx::query(sql), values, bind_opts) {
+        Ok(stmt) => stmt,
+        Err(e) => {
+            return ResponseEnum::Error {
+                tag: tag.clone(),
+                error: Box::from(e),
+            };
+        }
+    };
 
     match stmt.execute(&mut **tx).await {
         Ok(results) => ResponseEnum::ResponseItem {
@@ -356,98 +327,738 @@ async fn do_statements(
     sql: &str,
     tag: &Option<String>,
     values_batch: Vec<Value>,
+    bind_opts: &BindOptions,
 ) -> Vec<ResponseEnum> {
     let mut responses = vec![];
 
-    if values_batch.len() == 0 {
-        responses.push(do_single_statement(tx, sql, tag, None).await);
+    if values_batch.is_empty() {
+        responses.push(do_single_statement(tx, sql, tag, None, bind_opts).await);
     } else {
         for value in values_batch {
-            responses.push(do_single_statement(tx, sql, tag, Some(value)).await);
+            responses.push(do_single_statement(tx, sql, tag, Some(value), bind_opts).await);
         }
     }
     responses
 }
 
+// Inserts values_batch with as few multi-row INSERTs as max_allowed_packet allows.
+// Per row ids step from the first id of each chunk by auto_increment_increment.
+#[allow(clippy::type_complexity)]
+async fn do_bulk_insert(
+    tx: &mut Transaction<'_, MySql>,
+    sql: &str,
+    tag: &Option<String>,
+    values_batch: Vec<Value>,
+    mode: BulkMode,
+    bind_opts: &BindOptions,
+) -> Vec<ResponseEnum> {
+    let fail = |error: String| {
+        vec![ResponseEnum::Error {
+            tag: tag.clone(),
+            error: Box::from(error),
+        }]
+    };
+
+    let template = match InsertTemplate::parse(sql) {
+        Ok(template) => template,
+        Err(e) => return fail(e),
+    };
+    for (index, row) in values_batch.iter().enumerate() {
+        if row.as_array().map(Vec::len) != Some(template.placeholders) {
+            return fail(format!(
+                "valuesBatch entry {} must be an array of {} values.",
+                index, template.placeholders
+            ));
+        }
+    }
+
+    let (max_packet, increment) = match sqlx::query_as::<_, (i64, i64)>(
+        "SELECT CAST(@@max_allowed_packet AS SIGNED), CAST(@@auto_increment_increment AS SIGNED)",
+    )
+    .fetch_one(&mut **tx)
+    .await
+    {
+        Ok((max_packet, increment)) => (max_packet as usize, increment as u64),
+        Err(e) => return fail(e.to_string()),
+    };
+
+    let mut responses = vec![];
+    let mut rows_affected = 0;
+    let mut first_insert_id = 0;
+    let mut rows = values_batch.as_slice();
+    for chunk in template.chunks(&values_batch, max_packet) {
+        let (chunk_rows, rest) = rows.split_at(chunk);
+        rows = rest;
+        let chunk_sql = template.sql(chunk);
+        let values = Some(Value::Array(
+            chunk_rows
+                .iter()
+                .flat_map(|row| row.as_array().unwrap().iter().cloned())
+                .collect(),
+        ));
+        let stmt = match bind_values(sqlx::query(&chunk_sql), &values, bind_opts) {
+            Ok(stmt) => stmt,
+            Err(e) => return fail(e),
+        };
+        let results = match stmt.execute(&mut **tx).await {
+            Ok(results) => results,
+            Err(e) => {
+                responses.push(ResponseEnum::Error {
+                    tag: tag.clone(),
+                    error: Box::from(e),
+                });
+                return responses;
+            }
+        };
+
+        match mode {
+            BulkMode::Aggregated => {
+                rows_affected += results.rows_affected();
+                if first_insert_id == 0 {
+                    first_insert_id = results.last_insert_id();
+                }
+            }
+            BulkMode::PerRow => {
+                // Ignored or updated rows make it impossible to tell which row is which
+                if results.rows_affected() != chunk as u64 {
+                    responses.push(ResponseEnum::Error {
+                        tag: tag.clone(),
+                        error: Box::from(format!(
+                            "{} rows affected for {} rows inserted, use aggregated bulk results for this statement.",
+                            results.rows_affected(),
+                            chunk
+                        )),
+                    });
+                    return responses;
+                }
+                for row in 0..chunk as u64 {
+                    let last_insert_id = match results.last_insert_id() {
+                        0 => 0,
+                        id => id + row * increment,
+                    };
+                    responses.push(ResponseEnum::ResponseItem {
+                        tag: tag.clone(),
+                        response_item: ResponseItem::StatementSuccess {
+                            rows_affected: 1,
+                            last_insert_id,
+                        },
+                    });
+                }
+            }
+        }
+    }
+
+    if mode == BulkMode::Aggregated {
+        responses.push(ResponseEnum::ResponseItem {
+            tag: tag.clone(),
+            response_item: ResponseItem::StatementSuccess {
+                rows_affected,
+                last_insert_id: first_insert_id,
+            },
+        });
+    }
+    responses
+}
+
+const BATCH_INDEX_COLUMN: &str = "_batchIndex";
+
+// Runs the query once per entry of values_batch. Each run gets its own result set,
+// unless merge is set, in which case all rows go in one result set with the index of
+// the binding that produced them in BATCH_INDEX_COLUMN.
+#[allow(clippy::type_complexity)]
+async fn do_queries(
+    tx: &mut Transaction<'_, MySql>,
+    sql: &str,
+    tag: &Option<String>,
+    values_batch: Vec<Value>,
+    merge: bool,
+    bind_opts: &BindOptions,
+) -> Vec<ResponseEnum> {
+    if values_batch.is_empty() {
+        return vec![do_query(tx, sql, tag, &None, bind_opts).await];
+    }
+
+    let mut responses = vec![];
+    let mut merged = vec![];
+    for (index, value) in values_batch.into_iter().enumerate() {
+        let result = do_query(tx, sql, tag, &Some(value), bind_opts).await;
+        match result {
+            ResponseEnum::ResponseItem {
+                tag: _,
+                response_item: ResponseItem::QuerySuccess { result_set },
+            } if merge => {
+                for mut row in result_set {
+                    if let Some(map) = row.as_object_mut() {
+                        if map.insert(BATCH_INDEX_COLUMN.to_string(), json!(index)).is_some() {
+                            return vec![ResponseEnum::Error {
+                                tag: tag.clone(),
+                                error: Box::from(format!(
+                                    "Column {} clashes with the batch index, rename it to use mergeBatch.",
+                                    BATCH_INDEX_COLUMN
+                                )),
+                            }];
+                        }
+                    }
+                    merged.push(row);
+                }
+            }
+            ResponseEnum::Error { tag: _, error: _ } => {
+                responses.push(result);
+                return responses;
+            }
+            _ => responses.push(result),
+        }
+    }
+
+    if merge {
+        responses.push(ResponseEnum::ResponseItem {
+            tag: tag.clone(),
+            response_item: ResponseItem::QuerySuccess { result_set: merged },
+        });
+    }
+    responses
+}
+
+// Who and what a request runs as, passed down so logs and metrics can be attributed
+pub(crate) struct RequestContext<'a> {
+    pub profile: Option<&'a Profile>,
+    // Profile label for logs and metrics, never the connection string
+    pub profile_name: &'a str,
+    pub request_id: &'a str,
+    // Who the audit log says ran the statements
+    pub principal: &'a str,
+    pub slow_query: SlowQuery,
+}
+
+// One item as its own transaction, for the GET style routes. Goes through process so it
+// is audited, timed and logged like POST /.
+pub(crate) async fn run_item(
+    req: &HttpRequest,
+    config: &Config,
+    profile_name: &str,
+    item: ReqTransactionItem,
+) -> Result<ResponseItem, actix_web::Error> {
+    let profile = config
+        .profiles
+        .get(profile_name)
+        .ok_or_else(|| error::ErrorNotFound(format!("unknown profile '{}'", profile_name)))?;
+    let request_id = match req.extensions().get::<RequestId>() {
+        Some(RequestId(id)) => id.clone(),
+        None => logging::request_id(req.headers()),
+    };
+    let principal = audit::principal(req, config.audit.as_ref());
+    let ctx = RequestContext {
+        profile: Some(profile),
+        profile_name,
+        request_id: &request_id,
+        principal: &principal,
+        slow_query: config.slow_query(Some(profile)),
+    };
+    // Query items here are stored queries and table reads, the server makes sure they only read
+    let mut request = req_res::Request {
+        read_only: item.statement.is_none(),
+        transaction: vec![item],
+        json_values: true,
+        ..Default::default()
+    };
+    let (conn, host) = replicas::route(profile, profile_name, &mut request).await?;
+    let response = process(web::Json(request), conn, host.as_ref(), &ctx).await?;
+    match response.results.and_then(|results| results.into_iter().next()) {
+        Some(ResponseEnum::ResponseItem { response_item, .. }) => Ok(response_item),
+        Some(ResponseEnum::Error { error, .. }) => Err(error_status(error.as_ref())),
+        None => Err(error::ErrorInternalServerError("no result")),
+    }
+}
+
+// Bad values are the client's fault, as are most database errors
+fn error_status(err: &(dyn std::error::Error + 'static)) -> actix_web::Error {
+    let message = err.to_string();
+    match err.downcast_ref::<sqlx::Error>() {
+        Some(sqlx::Error::Database(db)) if db.kind() == ErrorKind::UniqueViolation => {
+            error::ErrorConflict(message)
+        }
+        Some(sqlx::Error::Database(_)) => error::ErrorBadRequest(message),
+        Some(_) => error::ErrorInternalServerError(message),
+        None => error::ErrorBadRequest(message),
+    }
+}
+
 async fn process(
     http_req: web::Json<req_res::Request>,
-    pool: &Pool<MySql>,
+    conn: PoolConnection<MySql>,
+    host: Option<&Host>,
+    ctx: &RequestContext<'_>,
 ) -> Result<Response, actix_web::Error> {
-    let mut tx = pool
-        .begin()
-        .await
-        .map_err(|err| error::ErrorInternalServerError(err.to_string()))?;
-    //let mut results = vec![];
-    let mut responses = vec![];
-    for (_, trx_item) in http_req.transaction.iter().enumerate() {
+    telemetry::traced(
+        telemetry::Span::child("process"),
+        run_process(http_req, conn, host, ctx),
+    )
+    .await
+}
+
+async fn run_process(
+    http_req: web::Json<req_res::Request>,
+    mut conn: PoolConnection<MySql>,
+    host: Option<&Host>,
+    ctx: &RequestContext<'_>,
+) -> Result<Response, actix_web::Error> {
+    let datetime = match ctx.profile {
+        Some(profile) => profile.datetime.merge(&http_req.datetime),
+        None => http_req.datetime.clone(),
+    }
+    .resolve()
+    .map_err(error::ErrorBadRequest)?;
+    let bind_opts = BindOptions {
+        json_values: http_req.json_values,
+        empty_list: http_req.empty_list,
+        datetime,
+    };
+
+    let zone = bind_opts.datetime.zone.as_ref().map(|zone| zone.sql_name());
+    if let Some(zone) = &zone {
+        sqlx::query("SET time_zone = ?")
+            .bind(zone)
+            .execute(&mut *conn)
+            .await
+            .map_err(|err| error::ErrorBadRequest(err.to_string()))?;
+    }
+
+    let result = process_transaction(&http_req, &mut conn, &bind_opts, ctx).await;
+    if let Some(host) = host {
+        if let Some(error) = lost_connection(&result, &mut conn).await {
+            host.lost(&error);
+        }
+    }
+
+    // Pooled connections are shared, so hand it back in UTC, as sqlx opened it
+    if zone.is_some()
+        && sqlx::query("SET time_zone = '+00:00'")
+            .execute(&mut *conn)
+            .await
+            .is_err()
+    {
+        let _ = conn.close().await;
+    }
+    result
+}
+
+// A connection error in an item, or a failed request whose connection no longer answers.
+// Other failures leave the host alone.
+async fn lost_connection(
+    result: &Result<Response, actix_web::Error>,
+    conn: &mut PoolConnection<MySql>,
+) -> Option<String> {
+    match result {
+        Ok(response) => response.results.iter().flatten().find_map(|result| match result {
+            ResponseEnum::Error { error, .. }
+                if metrics::error_category(error.as_ref()) == "connection" =>
+            {
+                Some(error.to_string())
+            }
+            _ => None,
+        }),
+        Err(e) if e.as_response_error().status_code().is_server_error() => {
+            conn.ping().await.err().map(|e| e.to_string())
+        }
+        Err(_) => None,
+    }
+}
+
+async fn process_transaction(
+    http_req: &req_res::Request,
+    conn: &mut PoolConnection<MySql>,
+    bind_opts: &BindOptions,
+    ctx: &RequestContext<'_>,
+) -> Result<Response, actix_web::Error> {
+    // Checked up front, so a bad item can't leave earlier ones run but unaudited
+    for trx_item in &http_req.transaction {
         if trx_item.query.is_some() && trx_item.statement.is_some() {
             return Err(error::ErrorBadRequest(
                 "exactly one of 'query' and 'statement' must be provided",
             ));
         }
+        if trx_item.values.is_some() && trx_item.values_batch.is_some() {
+            return Err(error::ErrorBadRequest(
+                "at most one of values and values_batch must be provided",
+            ));
+        }
+        if http_req.read_only && trx_item.statement.is_some() {
+            return Err(error::ErrorBadRequest(
+                "a readOnly transaction can't have statement items",
+            ));
+        }
+    }
 
+    // Applies to the next transaction only, the same as START TRANSACTION READ ONLY
+    if http_req.read_only {
+        sqlx::query("SET TRANSACTION READ ONLY")
+            .execute(&mut **conn)
+            .await
+            .map_err(|err| error::ErrorInternalServerError(err.to_string()))?;
+    }
+    let mut tx = conn
+        .begin()
+        .await
+        .map_err(|err| error::ErrorInternalServerError(err.to_string()))?;
+    //let mut results = vec![];
+    let mut responses = vec![];
+    let mut elapsed_ms = http_req.timing.then(Vec::new);
+    let mut audit_entries = vec![];
+    // Results only go into the cache once committed, they could have read our own writes
+    let mut to_cache = vec![];
+    // After a write the cache can't know what this transaction sees, so it isn't read
+    let mut wrote = false;
+    for (index, trx_item) in http_req.transaction.iter().enumerate() {
+        if shutdown::expired() {
+            return abandon(ctx, tx, &audit_entries).await;
+        }
         let values_batch = match (&trx_item.values, &trx_item.values_batch) {
-            (None, None) => vec![],
             (None, Some(y)) => y.clone(),
-            (Some(x), None) => vec![x.clone()],
-            (Some(_), Some(_)) => {
-                return Err(error::ErrorBadRequest(
-                    "at most one of values and values_batch must be provided",
-                ));
-            }
+            (Some(x), _) => vec![x.clone()],
+            (None, None) => vec![],
         };
 
-        if let Some(query) = &trx_item.query {
-            let result = do_query(&mut tx, query, &trx_item.tag, &trx_item.values).await;
-
-            match result {
-                ResponseEnum::ResponseItem {
-                    tag: _,
-                    response_item: _,
-                } => {
-                    responses.push(result);
-                }
-                ResponseEnum::Error { tag: _, error: _ } => {
-                    responses.push(result);
-                    println!("Rollin back");
-                    tx.rollback().await.unwrap();
-                    return Ok(Response {
-                        results: Some(responses),
-                    });
-                }
-            }
-        } else {
-            if let Some(statement_text) = trx_item.statement.as_ref() {
-                //do_query(&mut tx, query, &trx_item.values).await?
+        let start = Instant::now();
+        // A statement still running at the deadline is dropped, the rollback then waits for
+        // the server to finish it
+        let item = shutdown::before_deadline(async {
+            if let Some(query) = &trx_item.query {
+                let key = cache_key(ctx, trx_item, bind_opts);
+                let cached = key
+                    .as_ref()
+                    .filter(|_| !wrote)
+                    .and_then(|key| cache::get(ctx.profile_name, key));
+                let results = match cached {
+                    Some(rows) => vec![ResponseEnum::ResponseItem {
+                        tag: trx_item.tag.clone(),
+                        response_item: ResponseItem::QuerySuccess {
+                            result_set: rows.to_vec(),
+                        },
+                    }],
+                    None => {
+                        let results = do_queries(
+                            &mut tx,
+                            query,
+                            &trx_item.tag,
+                            values_batch,
+                            trx_item.merge_batch,
+                            bind_opts,
+                        )
+                        .await;
+                        if let (
+                            Some(key),
+                            [ResponseEnum::ResponseItem {
+                                response_item: ResponseItem::QuerySuccess { result_set },
+                                ..
+                            }],
+                        ) = (key, results.as_slice())
+                        {
+                            to_cache.push((key, result_set.clone(), trx_item));
+                        }
+                        results
+                    }
+                };
+                Some(("query", query, results))
+            } else if let Some(statement_text) = trx_item.statement.as_ref() {
+                wrote = true;
                 //Turn values into value batck of 1
-                let mut statment_results =
-                    do_statements(&mut tx, statement_text, &trx_item.tag, values_batch).await;
-
-                responses.append(&mut statment_results);
-
-                if let Some(x) = responses.iter().rev().next() {
-                    if let ResponseEnum::Error { tag: _, error: _ } = x {
-                        println!("Rollin back");
-                        tx.rollback().await.unwrap_or(());
-                        return Ok(Response {
-                            results: Some(responses),
-                        });
+                let results = match trx_item.bulk {
+                    Some(mode) if !values_batch.is_empty() => {
+                        do_bulk_insert(
+                            &mut tx,
+                            statement_text,
+                            &trx_item.tag,
+                            values_batch,
+                            mode,
+                            bind_opts,
+                        )
+                        .await
                     }
-                }
+                    _ => {
+                        do_statements(
+                            &mut tx,
+                            statement_text,
+                            &trx_item.tag,
+                            values_batch,
+                            bind_opts,
+                        )
+                        .await
+                    }
+                };
+                Some(("statement", statement_text, results))
+            } else {
+                None
             }
+        })
+        .await;
+        let (kind, sql, mut item_results) = match item {
+            Some(Some(item)) => item,
+            Some(None) => continue,
+            None => return abandon(ctx, tx, &audit_entries).await,
+        };
+        let elapsed = start.elapsed();
+        log_item(ctx, index, kind, elapsed);
+        log_slow(ctx, index, kind, sql, trx_item, elapsed);
+        metrics::observe_item(ctx.profile_name, kind, elapsed, &item_results);
+        if kind == "statement" {
+            audit_entries.push(audit_entry(index, sql, trx_item, &item_results));
         }
-    }
-    println!("Commitin");
 
-    tx.commit().await.unwrap_or(());
+        if let Some(timings) = elapsed_ms.as_mut() {
+            timings.resize(timings.len() + item_results.len(), millis(elapsed));
+        }
+        responses.append(&mut item_results);
+
+        if let Some(ResponseEnum::Error { tag: _, error: _ }) = responses.last() {
+            log_rollback(ctx, index);
+            tx.rollback().await.unwrap_or(());
+            audit::record(ctx, &audit_entries, audit::Outcome::Rollback);
+            return Ok(Response {
+                results: Some(responses),
+                elapsed_ms,
+            });
+        }
+    }
+    if shutdown::expired() {
+        return abandon(ctx, tx, &audit_entries).await;
+    }
+    match tx.commit().await {
+        Ok(()) => {
+            log::debug!(
+                request_id = ctx.request_id,
+                profile = ctx.profile_name;
+                "Committed transaction"
+            );
+            audit::record(ctx, &audit_entries, audit::Outcome::Commit);
+            update_cache(ctx, &http_req.transaction, to_cache);
+        }
+        Err(e) => {
+            log::error!(
+                request_id = ctx.request_id,
+                profile = ctx.profile_name,
+                category = metrics::error_category(&e);
+                "Commit failed"
+            );
+            audit::record(ctx, &audit_entries, audit::Outcome::CommitFailed);
+        }
+    }
     Ok(Response {
         results: Some(responses),
+        elapsed_ms,
+    })
+}
+
+// Only single queries of a profile are cached, batches and connection strings aren't
+fn cache_key(
+    ctx: &RequestContext<'_>,
+    trx_item: &ReqTransactionItem,
+    bind_opts: &BindOptions,
+) -> Option<String> {
+    trx_item.cache_ttl_secs?;
+    ctx.profile?;
+    if trx_item.values_batch.is_some() {
+        return None;
+    }
+    Some(cache::key(
+        ctx.profile_name,
+        trx_item.query.as_ref()?,
+        trx_item.values.as_ref(),
+        bind_opts,
+    ))
+}
+
+fn update_cache(
+    ctx: &RequestContext<'_>,
+    transaction: &[ReqTransactionItem],
+    to_cache: Vec<(String, Vec<Value>, &ReqTransactionItem)>,
+) {
+    for trx_item in transaction.iter().filter(|item| item.statement.is_some()) {
+        if !trx_item.invalidate_tables.is_empty() {
+            cache::invalidate(Some(ctx.profile_name), Some(&trx_item.invalidate_tables));
+        }
+    }
+    for (key, rows, trx_item) in to_cache {
+        let ttl = Duration::from_secs(trx_item.cache_ttl_secs.unwrap_or_default());
+        cache::insert(key, ctx.profile_name, rows, &trx_item.cache_tables, ttl);
+    }
+}
+
+// The drain deadline passed mid transaction, so it is rolled back rather than left for
+// the process exit to cut off
+async fn abandon(
+    ctx: &RequestContext<'_>,
+    tx: Transaction<'_, MySql>,
+    audit_entries: &[AuditEntry],
+) -> Result<Response, actix_web::Error> {
+    log::warn!(
+        request_id = ctx.request_id,
+        profile = ctx.profile_name;
+        "Rolled back transaction, shutting down"
+    );
+    tx.rollback().await.unwrap_or(());
+    audit::record(ctx, audit_entries, audit::Outcome::Rollback);
+    Err(error::ErrorServiceUnavailable(
+        "shutting down, the transaction was rolled back",
+    ))
+}
+
+// Milliseconds with microsecond resolution, as reported to clients
+fn millis(elapsed: Duration) -> f64 {
+    (elapsed.as_secs_f64() * 1_000_000.0).round() / 1000.0
+}
+
+fn log_item(ctx: &RequestContext<'_>, index: usize, kind: &str, elapsed: Duration) {
+    log::debug!(
+        request_id = ctx.request_id,
+        profile = ctx.profile_name,
+        item = index,
+        kind = kind,
+        elapsed_ms = elapsed.as_millis() as u64;
+        "Executed item"
+    );
+}
+
+fn audit_entry(
+    index: usize,
+    sql: &str,
+    trx_item: &ReqTransactionItem,
+    results: &[ResponseEnum],
+) -> AuditEntry {
+    let values = match (&trx_item.values, &trx_item.values_batch) {
+        (Some(values), _) => Some(values.clone()),
+        (None, Some(batch)) => Some(Value::Array(batch.clone())),
+        (None, None) => None,
+    };
+    let rows_affected = results
+        .iter()
+        .map(|result| match result {
+            ResponseEnum::ResponseItem {
+                tag: _,
+                response_item: ResponseItem::StatementSuccess { rows_affected, .. },
+            } => *rows_affected,
+            _ => 0,
+        })
+        .sum();
+    AuditEntry {
+        index,
+        tag: trx_item.tag.clone(),
+        sql: sql.to_string(),
+        param_hash: audit::param_hash(values.as_ref()),
+        rows_affected,
+        success: !results.iter().any(|r| matches!(r, ResponseEnum::Error { .. })),
+    }
+}
+
+// The SQL is logged as sent, with placeholders, so values stay out unless asked for
+fn log_slow(
+    ctx: &RequestContext<'_>,
+    index: usize,
+    kind: &str,
+    sql: &str,
+    trx_item: &ReqTransactionItem,
+    elapsed: Duration,
+) {
+    let Some(threshold_ms) = ctx.slow_query.threshold_ms else {
+        return;
+    };
+    if elapsed < Duration::from_millis(threshold_ms) {
+        return;
+    }
+    let values = match (ctx.slow_query.log_values, &trx_item.values, &trx_item.values_batch) {
+        (false, _, _) => "redacted".to_string(),
+        (true, Some(values), _) => values.to_string(),
+        (true, None, Some(batch)) => Value::Array(batch.clone()).to_string(),
+        (true, None, None) => "null".to_string(),
+    };
+    log::warn!(
+        target: "sqlxrg::slow",
+        request_id = ctx.request_id,
+        profile = ctx.profile_name,
+        item = index,
+        tag = trx_item.tag.as_deref().unwrap_or(""),
+        kind = kind,
+        elapsed_ms = millis(elapsed),
+        sql = sql,
+        values = values.as_str();
+        "Slow item"
+    );
+}
+
+// Only says which item failed, as error messages can quote the values
+fn log_rollback(ctx: &RequestContext<'_>, index: usize) {
+    log::warn!(
+        request_id = ctx.request_id,
+        profile = ctx.profile_name,
+        item = index;
+        "Rolled back transaction"
+    );
+}
+
+// Pools are cheap to clone, so the water park lock is never held across an await
+pub(crate) async fn get_pool(connection_string: &str) -> Result<Pool<MySql>, actix_web::Error> {
+    {
+        let lock = CONNECTION_WATER_PARK
+            .read()
+            .map_err(|_| error::ErrorInternalServerError("Could not get water park read lock"))?;
+        if let Some(pool) = lock.get(connection_string) {
+            //Should check if pool actually works here, the remove it from hashmap if it doesn't. Eg changed password.
+            return Ok(pool.clone());
+        }
+        //Read lock released at end of scope
+    }
+
+    let opts: MySqlConnectOptions = connection_string
+        .parse::<MySqlConnectOptions>()
+        .map_err(|err| error::ErrorBadRequest(err.to_string()))?;
+    let pool = MySqlPool::connect_with(opts)
+        .await
+        .map_err(|_| error::ErrorBadRequest("Connection string failed."))?;
+
+    let existing = {
+        let mut lock = CONNECTION_WATER_PARK
+            .write()
+            .map_err(|_| error::ErrorInternalServerError("Could not obtain write lock on water park."))?;
+        match lock.get(connection_string) {
+            Some(existing) => Some(existing.clone()),
+            None => {
+                lock.insert(connection_string.to_string(), pool.clone());
+                None
+            }
+        }
+    };
+    match existing {
+        // Another request connected first, so this pool's connections are closed, not leaked
+        Some(existing) => {
+            pool.close().await;
+            Ok(existing)
+        }
+        None => Ok(pool),
+    }
+}
+
+pub(crate) async fn acquire(
+    pool: &Pool<MySql>,
+    profile_name: &str,
+) -> Result<PoolConnection<MySql>, actix_web::Error> {
+    let _waiting = metrics::waiting(profile_name);
+    pool.acquire().await.map_err(|err| {
+        metrics::observe_error(profile_name, "connection");
+        error::ErrorInternalServerError(err.to_string())
     })
 }
 
 pub async fn handler(
     req: HttpRequest,
     body: web::Json<req_res::Request>,
+) -> Result<Response, actix_web::Error> {
+    // Continues the client's trace when it sent a traceparent header
+    let span = telemetry::Span::request("handler", req.headers());
+    telemetry::traced(span, handle(req, body)).await
+}
+
+async fn handle(
+    req: HttpRequest,
+    mut body: web::Json<req_res::Request>,
 ) -> Result<Response, actix_web::Error> {
     let _ = req
         .headers()
@@ -456,43 +1067,54 @@ pub async fn handler(
         .to_str()
         .map_err(|_| error::ErrorBadRequest("could not decode content-type into ascii bytes"))?
         .eq("application/json")
-        .then(|| true)
+        .then_some(true)
         .ok_or(error::ErrorBadRequest("could not decode content-type into ascii bytes"));
 
+    // Set by the middleware in lib::main, generated here when it isn't installed
+    let request_id = match req.extensions().get::<RequestId>() {
+        Some(RequestId(id)) => id.clone(),
+        None => logging::request_id(req.headers()),
+    };
+    let config = CONFIG.read().unwrap().clone();
+    let principal = audit::principal(&req, config.audit.as_ref());
+
+    // A configured profile takes precedence over a connection string sent by the client
+    if let Some(profile_name) = req.headers().get("profile") {
+        let profile_name = profile_name
+            .to_str()
+            .map_err(|_| error::ErrorBadRequest("could not decode profile into ascii bytes"))?;
+        let profile = config
+            .profiles
+            .get(profile_name)
+            .ok_or_else(|| error::ErrorNotFound(format!("unknown profile '{}'", profile_name)))?;
+        let (conn, host) = replicas::route(profile, profile_name, &mut body).await?;
+        let ctx = RequestContext {
+            profile: Some(profile),
+            profile_name,
+            request_id: &request_id,
+            principal: &principal,
+            slow_query: config.slow_query(Some(profile)),
+        };
+        return process(body, conn, host.as_ref(), &ctx).await;
+    }
+
     let connection_string = req
         .headers()
         .get("connection-string")
-        .ok_or(error::ErrorBadRequest("connection-string header missing"))?
+        .ok_or(error::ErrorBadRequest("connection-string or profile header missing"))?
         .to_str()
         .map_err(|_| error::ErrorBadRequest("could not decode content-type into ascii bytes"))?;
 
-    if let Ok(lock) = CONNECTION_WATER_PARK.try_read() {
-        if let Some(pool) = lock.get(connection_string) {
-            //Should check if pool actually works here, the remove it from hashmap if it doesn't. Eg changed password.
-            return process(body, pool).await;
-        } else {
-            //Release read lock
-            drop(lock);
-            if let Ok(mut lock) = CONNECTION_WATER_PARK.try_write() {
-                let opts: MySqlConnectOptions = connection_string
-                    .parse::<MySqlConnectOptions>()
-                    .map_err(|err| error::ErrorBadRequest(err.to_string()))?;
-                if let Ok(pool) = MySqlPool::connect_with(opts).await {
-                    lock.insert(connection_string.to_string(), pool);
-                    return process(body, lock.get(connection_string).unwrap()).await;
-                } else {
-                    return Err(error::ErrorBadRequest("Connection string failed."));
-                }
-            } else {
-                return Err(error::ErrorInternalServerError(
-                    "Could not obtain write lock on water park.",
-                ));
-            }
-        }
-    } else {
-        // Could not get read lock
-        return Err(error::ErrorInternalServerError(
-            "Could not get water park read lock",
-        ));
-    }
+    let pool = get_pool(connection_string)
+        .await
+        .inspect_err(|_| metrics::observe_error(metrics::ADHOC_PROFILE, "connection"))?;
+    let conn = acquire(&pool, metrics::ADHOC_PROFILE).await?;
+    let ctx = RequestContext {
+        profile: None,
+        profile_name: metrics::ADHOC_PROFILE,
+        request_id: &request_id,
+        principal: &principal,
+        slow_query: config.slow_query(None),
+    };
+    process(body, conn, None, &ctx).await
 }
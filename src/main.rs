@@ -16,7 +16,6 @@
 // This work was inspired by sqliterg by Germano Rizzo and heavily modified to suite mysql.
 // Modifications c) 2023-, Nexon Asia Pacific also under apache 2.0 license
 
-use sqlxrg;
-fn main () {
-    let _ = sqlxrg::main();
+fn main () -> std::io::Result<()> {
+    sqlxrg::main()
 }
\ No newline at end of file
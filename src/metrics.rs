@@ -0,0 +1,254 @@
+// Original copyright notice
+// Copyright (c) 2023-, Germano Rizzo <oss /AT/ germanorizzo /DOT/ it>
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+// This work was inspired by sqliterg by Germano Rizzo and heavily modified to suite mysql.
+// Modifications c) 2023-, Nexon Asia Pacific also under apache 2.0 license
+
+use std::{
+    collections::HashMap,
+    future::Future,
+    time::{Duration, Instant},
+};
+
+use actix_web::{
+    dev::{Service, ServiceRequest, ServiceResponse},
+    http::StatusCode,
+    HttpResponse,
+};
+use lazy_static::lazy_static;
+use prometheus::{
+    register_histogram_vec_with_registry, register_int_counter_vec_with_registry,
+    register_int_gauge_vec_with_registry, Encoder, HistogramVec, IntCounterVec, IntGaugeVec,
+    Registry, TextEncoder,
+};
+
+use crate::{
+    req_res::{ResponseEnum, ResponseItem},
+    statics::{CONFIG, CONNECTION_WATER_PARK},
+};
+
+// Label for requests and pools that came from a connection-string header. Connection
+// strings hold credentials, so they never become labels.
+pub const ADHOC_PROFILE: &str = "adhoc";
+
+lazy_static! {
+    pub static ref REGISTRY: Registry = Registry::new();
+    static ref HTTP_REQUESTS: IntCounterVec = register_int_counter_vec_with_registry!(
+        "sqlxrg_http_requests_total",
+        "HTTP requests by route and outcome",
+        &["route", "outcome"],
+        REGISTRY
+    )
+    .unwrap();
+    static ref HTTP_DURATION: HistogramVec = register_histogram_vec_with_registry!(
+        "sqlxrg_http_request_duration_seconds",
+        "HTTP request latency by route and outcome",
+        &["route", "outcome"],
+        REGISTRY
+    )
+    .unwrap();
+    static ref ITEM_DURATION: HistogramVec = register_histogram_vec_with_registry!(
+        "sqlxrg_item_duration_seconds",
+        "Execution time of transaction items",
+        &["profile", "kind"],
+        REGISTRY
+    )
+    .unwrap();
+    static ref ROWS_RETURNED: IntCounterVec = register_int_counter_vec_with_registry!(
+        "sqlxrg_rows_returned_total",
+        "Rows returned by query items",
+        &["profile"],
+        REGISTRY
+    )
+    .unwrap();
+    static ref ROWS_AFFECTED: IntCounterVec = register_int_counter_vec_with_registry!(
+        "sqlxrg_rows_affected_total",
+        "Rows affected by statement items",
+        &["profile"],
+        REGISTRY
+    )
+    .unwrap();
+    static ref ERRORS: IntCounterVec = register_int_counter_vec_with_registry!(
+        "sqlxrg_errors_total",
+        "Errors by category",
+        &["profile", "category"],
+        REGISTRY
+    )
+    .unwrap();
+    static ref POOL_CONNECTIONS: IntGaugeVec = register_int_gauge_vec_with_registry!(
+        "sqlxrg_pool_connections",
+        "Connections held by the pool, by state",
+        &["profile", "state"],
+        REGISTRY
+    )
+    .unwrap();
+    static ref CACHE_LOOKUPS: IntCounterVec = register_int_counter_vec_with_registry!(
+        "sqlxrg_cache_lookups_total",
+        "Result cache lookups by outcome",
+        &["profile", "outcome"],
+        REGISTRY
+    )
+    .unwrap();
+    static ref POOL_WAITERS: IntGaugeVec = register_int_gauge_vec_with_registry!(
+        "sqlxrg_pool_waiters",
+        "Requests waiting to acquire a connection from the pool",
+        &["profile"],
+        REGISTRY
+    )
+    .unwrap();
+}
+
+fn outcome(status: Option<StatusCode>) -> &'static str {
+    match status {
+        Some(status) if status.is_client_error() => "client_error",
+        Some(status) if status.is_server_error() => "server_error",
+        Some(_) => "success",
+        None => "server_error",
+    }
+}
+
+pub fn observe_request(route: &str, status: Option<StatusCode>, elapsed: Duration) {
+    let labels = [route, outcome(status)];
+    HTTP_REQUESTS.with_label_values(&labels).inc();
+    HTTP_DURATION
+        .with_label_values(&labels)
+        .observe(elapsed.as_secs_f64());
+}
+
+// Counts and times every request, by route pattern so paths can't explode the labels
+pub fn middleware<S, B>(
+    req: ServiceRequest,
+    srv: &S,
+) -> impl Future<Output = Result<ServiceResponse<B>, actix_web::Error>>
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = actix_web::Error>,
+{
+    let start = Instant::now();
+    let route = req
+        .match_pattern()
+        .unwrap_or_else(|| "unmatched".to_string());
+    let fut = srv.call(req);
+    async move {
+        let res = fut.await;
+        observe_request(&route, res.as_ref().ok().map(|r| r.status()), start.elapsed());
+        res
+    }
+}
+
+pub fn observe_item(profile: &str, kind: &str, elapsed: Duration, results: &[ResponseEnum]) {
+    ITEM_DURATION
+        .with_label_values(&[profile, kind])
+        .observe(elapsed.as_secs_f64());
+    for result in results {
+        match result {
+            ResponseEnum::ResponseItem {
+                tag: _,
+                response_item: ResponseItem::QuerySuccess { result_set },
+            } => ROWS_RETURNED
+                .with_label_values(&[profile])
+                .inc_by(result_set.len() as u64),
+            ResponseEnum::ResponseItem {
+                tag: _,
+                response_item: ResponseItem::StatementSuccess { rows_affected, .. },
+            } => ROWS_AFFECTED
+                .with_label_values(&[profile])
+                .inc_by(*rows_affected),
+            ResponseEnum::Error { tag: _, error } => observe_error(profile, error_category(error.as_ref())),
+        }
+    }
+}
+
+pub fn observe_cache(profile: &str, hit: bool) {
+    CACHE_LOOKUPS
+        .with_label_values(&[profile, if hit { "hit" } else { "miss" }])
+        .inc();
+}
+
+pub fn observe_error(profile: &str, category: &str) {
+    ERRORS.with_label_values(&[profile, category]).inc();
+}
+
+// Errors that don't come from sqlx are ours, raised while checking the request
+pub fn error_category(error: &(dyn std::error::Error + 'static)) -> &'static str {
+    match error.downcast_ref::<sqlx::Error>() {
+        Some(sqlx::Error::Database(_)) => "database",
+        Some(
+            sqlx::Error::Io(_)
+            | sqlx::Error::Tls(_)
+            | sqlx::Error::PoolTimedOut
+            | sqlx::Error::PoolClosed
+            | sqlx::Error::WorkerCrashed,
+        ) => "connection",
+        Some(_) => "driver",
+        None => "request",
+    }
+}
+
+pub fn waiting(profile: &str) -> WaitGuard {
+    POOL_WAITERS.with_label_values(&[profile]).inc();
+    WaitGuard(profile.to_string())
+}
+
+// Counts a waiter until dropped, so a cancelled request doesn't leave the gauge up
+pub struct WaitGuard(String);
+
+impl Drop for WaitGuard {
+    fn drop(&mut self) {
+        POOL_WAITERS.with_label_values(&[&self.0]).dec();
+    }
+}
+
+// Pool sizes are read when scraped rather than tracked. Replica and failover host pools
+// count towards their profile.
+fn collect_pools() {
+    let config = CONFIG.read().unwrap().clone();
+    let names: HashMap<String, &str> = config
+        .profiles
+        .iter()
+        .flat_map(|(name, profile)| profile.pool_urls().into_iter().map(move |url| (url, name.as_str())))
+        .collect();
+
+    let mut sizes: HashMap<&str, (i64, i64)> = HashMap::new();
+    let water_park = CONNECTION_WATER_PARK.read().unwrap();
+    for (connection_string, pool) in water_park.iter() {
+        let profile = names
+            .get(connection_string)
+            .copied()
+            .unwrap_or(ADHOC_PROFILE);
+        let entry = sizes.entry(profile).or_default();
+        entry.0 += i64::from(pool.size());
+        entry.1 += pool.num_idle() as i64;
+    }
+
+    POOL_CONNECTIONS.reset();
+    for (profile, (size, idle)) in sizes {
+        POOL_CONNECTIONS.with_label_values(&[profile, "total"]).set(size);
+        POOL_CONNECTIONS.with_label_values(&[profile, "idle"]).set(idle);
+        POOL_CONNECTIONS
+            .with_label_values(&[profile, "in_use"])
+            .set(size - idle);
+    }
+}
+
+pub async fn handler() -> HttpResponse {
+    collect_pools();
+    let mut buffer = vec![];
+    if let Err(e) = TextEncoder::new().encode(&REGISTRY.gather(), &mut buffer) {
+        return HttpResponse::InternalServerError().body(e.to_string());
+    }
+    HttpResponse::Ok()
+        .content_type(prometheus::TEXT_FORMAT)
+        .body(buffer)
+}
@@ -0,0 +1,430 @@
+// Original copyright notice
+// Copyright (c) 2023-, Germano Rizzo <oss /AT/ germanorizzo /DOT/ it>
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+// This work was inspired by sqliterg by Germano Rizzo and heavily modified to suite mysql.
+// Modifications c) 2023-, Nexon Asia Pacific also under apache 2.0 license
+
+use std::str::FromStr;
+
+use base64::Engine;
+use rust_decimal::Decimal;
+use serde::Deserialize;
+use serde_json::{Map, Number, Value};
+use sqlx::{
+    mysql::MySqlArguments,
+    query::Query,
+    types::{
+        chrono::{DateTime, NaiveDate, NaiveDateTime, NaiveTime, Utc},
+        Json,
+    },
+    MySql,
+};
+
+use crate::datetime::DateTimeOptions;
+
+pub type MySqlQuery<'q> = Query<'q, MySql, MySqlArguments>;
+
+// What to do when an empty array is bound to a ?... placeholder
+#[derive(Debug, Default, Clone, Copy, PartialEq, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub enum EmptyList {
+    #[default]
+    Error,
+    // Expands to NULL, so both IN (?...) and NOT IN (?...) match no rows
+    AlwaysFalse,
+}
+
+// Options that change how json values are turned into bind parameters
+#[derive(Debug, Default, Clone)]
+pub struct BindOptions {
+    // Bind plain json objects and arrays as json text instead of rejecting them
+    pub json_values: bool,
+    pub empty_list: EmptyList,
+    pub datetime: DateTimeOptions,
+}
+
+const LIST_MARKER: &str = "?...";
+
+// Rewrites every ?... placeholder into as many ? as there are elements in the array
+// bound to it, and flattens the values to match. Returns None when there is nothing
+// to expand, so the common case doesn't copy the sql or the values.
+pub fn expand_lists(
+    sql: &str,
+    values: &Option<Value>,
+    opts: &BindOptions,
+) -> Result<Option<(String, Option<Value>)>, String> {
+    if !sql.contains(LIST_MARKER) {
+        return Ok(None);
+    }
+    let arr: &[Value] = match values {
+        Some(Value::Array(arr)) => arr,
+        Some(_) => return Err("Values must be array.".to_string()),
+        None => &[],
+    };
+
+    let bytes = sql.as_bytes();
+    let mut out = String::with_capacity(sql.len());
+    let mut flat = vec![];
+    let mut expanded = false;
+    let mut placeholder = 0;
+    let mut copied = 0;
+    let mut offsets = code_offsets(sql);
+    while let Some(i) = offsets.next() {
+        if bytes[i] != b'?' {
+            continue;
+        }
+        if !sql[i..].starts_with(LIST_MARKER) {
+            flat.extend(arr.get(placeholder).cloned());
+            placeholder += 1;
+            continue;
+        }
+        out.push_str(&sql[copied..i]);
+        match arr.get(placeholder) {
+            Some(Value::Array(items)) if items.is_empty() => match opts.empty_list {
+                EmptyList::Error => {
+                    return Err(format!(
+                        "Empty array bound to list placeholder {}.",
+                        placeholder + 1
+                    ))
+                }
+                EmptyList::AlwaysFalse => out.push_str("NULL"),
+            },
+            Some(Value::Array(items)) => {
+                out.push_str(&vec!["?"; items.len()].join(", "));
+                flat.extend(items.iter().cloned());
+            }
+            _ => {
+                return Err(format!(
+                    "List placeholder {} must be bound to an array.",
+                    placeholder + 1
+                ))
+            }
+        }
+        expanded = true;
+        placeholder += 1;
+        copied = i + LIST_MARKER.len();
+        // Step over the dots of the marker
+        offsets.nth(LIST_MARKER.len() - 2);
+    }
+    if !expanded {
+        return Ok(None);
+    }
+    out.push_str(&sql[copied..]);
+    // Surplus values are passed through so the database reports the mismatch as before
+    flat.extend(arr.iter().skip(placeholder).cloned());
+    Ok(Some((out, Some(Value::Array(flat)))))
+}
+
+// Placeholders of sql in order, true for a ?... list placeholder
+pub fn placeholders(sql: &str) -> Vec<bool> {
+    code_offsets(sql)
+        .filter(|&i| sql.as_bytes()[i] == b'?')
+        .map(|i| sql[i..].starts_with(LIST_MARKER))
+        .collect()
+}
+
+// The words of sql outside literals, identifiers in backticks and comments, upper-cased
+pub fn keywords(sql: &str) -> Vec<String> {
+    let mut code = String::with_capacity(sql.len());
+    let mut next = 0;
+    for i in code_offsets(sql) {
+        if i != next {
+            code.push(' ');
+        }
+        code.push(sql.as_bytes()[i] as char);
+        next = i + 1;
+    }
+    code.split(|c: char| !(c.is_ascii_alphanumeric() || c == '_' || c == '$'))
+        .filter(|word| !word.is_empty())
+        .map(str::to_ascii_uppercase)
+        .collect()
+}
+
+// Walks the byte offsets of sql that are code, stepping over string literals, quoted
+// identifiers and comments, so placeholders and keywords inside them are never seen
+pub(crate) struct CodeOffsets<'s> {
+    sql: &'s str,
+    i: usize,
+}
+
+pub(crate) fn code_offsets(sql: &str) -> CodeOffsets<'_> {
+    CodeOffsets { sql, i: 0 }
+}
+
+impl Iterator for CodeOffsets<'_> {
+    type Item = usize;
+
+    fn next(&mut self) -> Option<usize> {
+        let bytes = self.sql.as_bytes();
+        while self.i < bytes.len() {
+            let i = self.i;
+            match bytes[i] {
+                b'\'' | b'"' | b'`' => self.i = skip_quoted(bytes, i),
+                b'#' => self.i = skip_line(bytes, i),
+                b'-' if self.sql[i..].starts_with("--")
+                    && bytes.get(i + 2).is_none_or(u8::is_ascii_whitespace) =>
+                {
+                    self.i = skip_line(bytes, i)
+                }
+                b'/' if self.sql[i..].starts_with("/*") => {
+                    self.i = self.sql[i + 2..]
+                        .find("*/")
+                        .map_or(bytes.len(), |end| i + end + 4)
+                }
+                _ => {
+                    self.i += 1;
+                    return Some(i);
+                }
+            }
+        }
+        None
+    }
+}
+
+// Returns the index just past the closing quote, honouring backslash and doubled quote escapes
+fn skip_quoted(bytes: &[u8], start: usize) -> usize {
+    let quote = bytes[start];
+    let mut i = start + 1;
+    while i < bytes.len() {
+        if bytes[i] == b'\\' && quote != b'`' {
+            i += 2;
+        } else if bytes[i] == quote {
+            if bytes.get(i + 1) == Some(&quote) {
+                i += 2;
+            } else {
+                return i + 1;
+            }
+        } else {
+            i += 1;
+        }
+    }
+    bytes.len()
+}
+
+fn skip_line(bytes: &[u8], start: usize) -> usize {
+    bytes[start..]
+        .iter()
+        .position(|b| *b == b'\n')
+        .map_or(bytes.len(), |end| start + end)
+}
+
+// SQL with quoted strings and numbers replaced by ? and comments dropped, for when the
+// text itself may hold values
+pub fn redact_literals(sql: &str) -> String {
+    let bytes = sql.as_bytes();
+    let is_word = |b: u8| b.is_ascii_alphanumeric() || b == b'_' || b == b'$';
+    let mut redacted = String::with_capacity(sql.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        match bytes[i] {
+            b'\'' | b'"' => {
+                i = skip_quoted(bytes, i);
+                redacted.push('?');
+            }
+            b'`' => {
+                let end = skip_quoted(bytes, i);
+                redacted.push_str(&sql[i..end]);
+                i = end;
+            }
+            b'#' => i = skip_line(bytes, i),
+            b'-' if sql[i..].starts_with("--")
+                && bytes.get(i + 2).is_none_or(u8::is_ascii_whitespace) =>
+            {
+                i = skip_line(bytes, i)
+            }
+            b'/' if sql[i..].starts_with("/*") => {
+                i = sql[i + 2..].find("*/").map_or(bytes.len(), |end| i + end + 4);
+                redacted.push(' ');
+            }
+            b'0'..=b'9' if i == 0 || !is_word(bytes[i - 1]) => {
+                while i < bytes.len() && (is_word(bytes[i]) || bytes[i] == b'.') {
+                    i += 1;
+                }
+                redacted.push('?');
+            }
+            _ => {
+                let ch = sql[i..].chars().next().unwrap();
+                redacted.push(ch);
+                i += ch.len_utf8();
+            }
+        }
+    }
+    redacted
+}
+
+pub fn bind_values<'q>(
+    mut qry: MySqlQuery<'q>,
+    values: &Option<Value>,
+    opts: &BindOptions,
+) -> Result<MySqlQuery<'q>, String> {
+    match values {
+        Some(Value::Array(arr)) => {
+            for value in arr {
+                qry = bind_value(qry, value, opts)?;
+            }
+            Ok(qry)
+        }
+        Some(_) => Err("Values must be array.".to_string()),
+        None => Ok(qry),
+    }
+}
+
+fn bind_value<'q>(
+    qry: MySqlQuery<'q>,
+    value: &Value,
+    opts: &BindOptions,
+) -> Result<MySqlQuery<'q>, String> {
+    match value {
+        Value::Null => Ok(qry.bind(Option::<String>::None)),
+        Value::String(s) => Ok(qry.bind(s.clone())),
+        Value::Bool(b) => Ok(qry.bind(*b)),
+        Value::Number(n) => bind_number(qry, n),
+        Value::Object(obj) => match typed_param(obj) {
+            Some((kind, val)) => bind_typed(qry, kind, val, opts),
+            None if opts.json_values => Ok(qry.bind(value.to_string())),
+            None => Err(exhausted()),
+        },
+        Value::Array(_) if opts.json_values => Ok(qry.bind(value.to_string())),
+        Value::Array(_) => Err(exhausted()),
+    }
+}
+
+fn exhausted() -> String {
+    "Json value varient exhausted, please make sure all values are either a string, number, boolean, typed parameter ({\"type\": ..., \"value\": ...}), or set jsonValues to bind objects and arrays as json.".to_string()
+}
+
+fn bind_number<'q>(qry: MySqlQuery<'q>, n: &Number) -> Result<MySqlQuery<'q>, String> {
+    if let Some(x) = n.as_u64() {
+        Ok(qry.bind(x))
+    } else if let Some(x) = n.as_i64() {
+        Ok(qry.bind(x))
+    } else if let Some(x) = n.as_f64() {
+        Ok(qry.bind(x))
+    } else {
+        Err("Could not deserialise number field in values.".to_string())
+    }
+}
+
+// A typed parameter is an object with exactly a string "type" and a "value"
+fn typed_param(obj: &Map<String, Value>) -> Option<(&str, &Value)> {
+    if obj.len() != 2 {
+        return None;
+    }
+    match (obj.get("type"), obj.get("value")) {
+        (Some(Value::String(kind)), Some(val)) => Some((kind.as_str(), val)),
+        _ => None,
+    }
+}
+
+// A null value binds a typed NULL, anything else goes through parse
+fn typed<T>(
+    val: &Value,
+    parse: impl FnOnce(&Value) -> Result<T, String>,
+) -> Result<Option<T>, String> {
+    if val.is_null() {
+        Ok(None)
+    } else {
+        parse(val).map(Some)
+    }
+}
+
+// The types bind_typed understands, kept in step with it
+pub const PARAMETER_TYPES: &[&str] = &[
+    "string", "text", "int", "integer", "bigint", "uint", "unsigned", "float", "double", "bool",
+    "boolean", "decimal", "date", "time", "datetime", "timestamp", "json", "base64", "hex",
+];
+
+fn bind_typed<'q>(
+    qry: MySqlQuery<'q>,
+    kind: &str,
+    val: &Value,
+    opts: &BindOptions,
+) -> Result<MySqlQuery<'q>, String> {
+    let qry = match kind.to_ascii_lowercase().as_str() {
+        "string" | "text" => qry.bind(typed(val, |v| as_str(v, kind).map(str::to_string))?),
+        "int" | "integer" | "bigint" => qry.bind(typed(val, |v| parse_with(v, kind, Value::as_i64))?),
+        "uint" | "unsigned" => qry.bind(typed(val, |v| parse_with(v, kind, Value::as_u64))?),
+        "float" | "double" => qry.bind(typed(val, |v| parse_with(v, kind, Value::as_f64))?),
+        "bool" | "boolean" => qry.bind(typed(val, |v| parse_with(v, kind, Value::as_bool))?),
+        "decimal" => qry.bind(typed(val, parse_decimal)?),
+        "date" => qry.bind(typed(val, |v| {
+            NaiveDate::parse_from_str(as_str(v, kind)?, "%Y-%m-%d").map_err(|e| e.to_string())
+        })?),
+        "time" => qry.bind(typed(val, |v| {
+            NaiveTime::parse_from_str(as_str(v, kind)?, "%H:%M:%S%.f").map_err(|e| e.to_string())
+        })?),
+        "datetime" | "timestamp" => qry.bind(typed(val, |v| parse_datetime(v, &opts.datetime))?),
+        "json" => qry.bind(typed(val, |v| Ok(Json(v.clone())))?),
+        "base64" => qry.bind(typed(val, |v| {
+            base64::engine::general_purpose::STANDARD
+                .decode(as_str(v, kind)?)
+                .map_err(|e| e.to_string())
+        })?),
+        "hex" => qry.bind(typed(val, |v| hex::decode(as_str(v, kind)?).map_err(|e| e.to_string()))?),
+        _ => return Err(format!("Unknown parameter type '{}'.", kind)),
+    };
+    Ok(qry)
+}
+
+fn as_str<'v>(val: &'v Value, kind: &str) -> Result<&'v str, String> {
+    val.as_str()
+        .ok_or_else(|| format!("Expected a string value for parameter type '{}'.", kind))
+}
+
+// Accepts the native json representation or its string form, eg 5 or "5"
+fn parse_with<T: FromStr>(
+    val: &Value,
+    kind: &str,
+    native: impl FnOnce(&Value) -> Option<T>,
+) -> Result<T, String> {
+    if let Some(x) = native(val) {
+        return Ok(x);
+    }
+    as_str(val, kind)?
+        .trim()
+        .parse::<T>()
+        .map_err(|_| format!("Could not parse '{}' as parameter type '{}'.", val, kind))
+}
+
+fn parse_decimal(val: &Value) -> Result<Decimal, String> {
+    let text = match val {
+        Value::String(s) => s.trim().to_string(),
+        Value::Number(n) => n.to_string(),
+        _ => return Err("Expected a string or number value for parameter type 'decimal'.".to_string()),
+    };
+    Decimal::from_str(&text)
+        .or_else(|_| Decimal::from_scientific(&text))
+        .map_err(|e| e.to_string())
+}
+
+// Strings carrying an offset are converted to the session time zone, naive ones are
+// bound as given. Numbers are taken as seconds since the unix epoch.
+fn parse_datetime(val: &Value, datetime: &DateTimeOptions) -> Result<NaiveDateTime, String> {
+    if let Some(secs) = val.as_i64() {
+        return DateTime::<Utc>::from_timestamp(secs, 0)
+            .map(|d| datetime.to_local(&d.fixed_offset()))
+            .ok_or_else(|| format!("Epoch value {} is out of range.", secs));
+    }
+    let text = as_str(val, "datetime")?.trim();
+    if let Ok(d) = DateTime::parse_from_rfc3339(text) {
+        return Ok(datetime.to_local(&d));
+    }
+    for fmt in ["%Y-%m-%d %H:%M:%S%.f", "%Y-%m-%dT%H:%M:%S%.f"] {
+        if let Ok(d) = NaiveDateTime::parse_from_str(text, fmt) {
+            return Ok(d);
+        }
+    }
+    Err(format!("Could not parse '{}' as a datetime.", text))
+}
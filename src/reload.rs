@@ -0,0 +1,200 @@
+// Original copyright notice
+// Copyright (c) 2023-, Germano Rizzo <oss /AT/ germanorizzo /DOT/ it>
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+// This work was inspired by sqliterg by Germano Rizzo and heavily modified to suite mysql.
+// Modifications c) 2023-, Nexon Asia Pacific also under apache 2.0 license
+
+use std::{
+    collections::BTreeSet,
+    sync::{Arc, Mutex, OnceLock},
+};
+
+use actix_web::HttpResponse;
+use serde::Serialize;
+use serde_json::json;
+
+use crate::{
+    audit, cache,
+    config::{self, Config},
+    rest,
+    statics::{CONFIG, CONNECTION_WATER_PARK},
+    tls::CertResolver,
+};
+
+struct Reloader {
+    path: Option<String>,
+    certs: Option<Arc<CertResolver>>,
+}
+
+static RELOADER: OnceLock<Reloader> = OnceLock::new();
+// One reload at a time, so two can't both decide which pools are stale
+static RELOADING: Mutex<()> = Mutex::new(());
+
+// What a reload did, logged and returned by the admin endpoint
+#[derive(Debug, Default, PartialEq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ReloadSummary {
+    pub added: Vec<String>,
+    pub removed: Vec<String>,
+    // Profiles whose connection changed, so their pool is rebuilt
+    pub rebuilt: Vec<String>,
+    pub closed_pools: usize,
+    // Sections read when the listeners start, which changed but are not applied yet
+    pub restart_needed: Vec<&'static str>,
+}
+
+pub fn init(path: Option<String>, certs: Option<Arc<CertResolver>>) {
+    let _ = RELOADER.set(Reloader { path, certs });
+}
+
+// Compares profiles by name, the connection URLs deciding whether the pools can be kept
+pub fn diff(old: &Config, new: &Config) -> ReloadSummary {
+    let mut summary = ReloadSummary::default();
+    for (name, profile) in &new.profiles {
+        match old.profiles.get(name) {
+            None => summary.added.push(name.clone()),
+            Some(old) if old.pool_urls() != profile.pool_urls() => {
+                summary.rebuilt.push(name.clone())
+            }
+            Some(_) => {}
+        }
+    }
+    summary.removed = old
+        .profiles
+        .keys()
+        .filter(|name| !new.profiles.contains_key(*name))
+        .cloned()
+        .collect();
+    // cors, corsPolicies and console are read on every request, the rest when the listeners
+    // start
+    let sections = [
+        ("staticFiles", old.static_files != new.static_files),
+        ("telemetry", old.telemetry != new.telemetry),
+        ("tls", old.tls != new.tls),
+    ];
+    summary.restart_needed = sections
+        .iter()
+        .filter(|(_, changed)| *changed)
+        .map(|(name, _)| *name)
+        .collect();
+    summary
+}
+
+// Reads the config file again. Nothing is applied unless all of it is valid, and pools
+// are only closed once no profile uses their connection any more.
+pub async fn reload() -> Result<ReloadSummary, String> {
+    let reloader = RELOADER.get().ok_or("reload is not set up")?;
+    let path = reloader.path.as_ref().ok_or("started without --config")?;
+    let (mut summary, stale) = {
+        let _reloading = RELOADING.lock().unwrap();
+        let new = config::load_config(path)?;
+        let old = CONFIG.read().unwrap().clone();
+        if old.audit != new.audit {
+            audit::init(new.audit.as_ref()).map_err(|e| format!("could not open audit log: {}", e))?;
+        }
+        let summary = diff(&old, &new);
+        // Results of a profile that now points elsewhere, or is gone, must not be served
+        for name in summary.removed.iter().chain(&summary.rebuilt) {
+            cache::invalidate(Some(name), None);
+            rest::forget_tables(&old.profiles[name].connection_url());
+        }
+        let urls: BTreeSet<String> = new.profiles.values().flat_map(|p| p.pool_urls()).collect();
+        *CONFIG.write().unwrap() = Arc::new(new);
+
+        let mut water_park = CONNECTION_WATER_PARK.write().unwrap();
+        let stale: Vec<_> = old
+            .profiles
+            .values()
+            .flat_map(|p| p.pool_urls())
+            .filter(|url| !urls.contains(url))
+            .filter_map(|url| water_park.remove(&url))
+            .collect();
+        (summary, stale)
+    };
+    // Running transactions keep their connection, close waits for them to come back
+    for pool in &stale {
+        pool.close().await;
+    }
+    summary.closed_pools = stale.len();
+    Ok(summary)
+}
+
+// Reads the certificate and key again from the paths the listeners started with, whether
+// or not the config file could be reloaded, so a broken file doesn't hold up a renewal
+fn reload_certs() {
+    if let Some(certs) = RELOADER.get().and_then(|reloader| reloader.certs.as_ref()) {
+        match certs.reload() {
+            Ok(()) => log::info!("Reloaded TLS certificate"),
+            Err(e) => log::error!("Could not reload TLS certificate, keeping the old one: {}", e),
+        }
+    }
+}
+
+async fn reload_and_log() -> Result<ReloadSummary, String> {
+    let reloaded = reload().await;
+    reload_certs();
+    match reloaded {
+        Ok(summary) => {
+            log::info!(
+                "Reloaded config: {} added, {} removed, {} rebuilt, {} pool(s) closed",
+                summary.added.len(),
+                summary.removed.len(),
+                summary.rebuilt.len(),
+                summary.closed_pools
+            );
+            if !summary.restart_needed.is_empty() {
+                log::warn!(
+                    "Changes to {} apply after a restart",
+                    summary.restart_needed.join(", ")
+                );
+            }
+            Ok(summary)
+        }
+        Err(e) => {
+            log::error!("Could not reload config, keeping the old one: {}", e);
+            Err(e)
+        }
+    }
+}
+
+// POST /admin/reload
+pub async fn handler() -> HttpResponse {
+    match reload_and_log().await {
+        Ok(summary) => HttpResponse::Ok().json(summary),
+        Err(e) => HttpResponse::UnprocessableEntity().json(json!({ "error": e })),
+    }
+}
+
+// The config file and the TLS certificate are read again on SIGHUP
+#[cfg(unix)]
+pub fn reload_on_sighup() -> std::io::Result<()> {
+    use actix_web::rt::{
+        self,
+        signal::unix::{signal, SignalKind},
+    };
+
+    let mut hangup = signal(SignalKind::hangup())?;
+    rt::spawn(async move {
+        while hangup.recv().await.is_some() {
+            let _ = reload_and_log().await;
+        }
+    });
+    Ok(())
+}
+
+#[cfg(not(unix))]
+pub fn reload_on_sighup() -> std::io::Result<()> {
+    Ok(())
+}
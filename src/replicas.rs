@@ -0,0 +1,220 @@
+// Original copyright notice
+// Copyright (c) 2023-, Germano Rizzo <oss /AT/ germanorizzo /DOT/ it>
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+// This work was inspired by sqliterg by Germano Rizzo and heavily modified to suite mysql.
+// Modifications c) 2023-, Nexon Asia Pacific also under apache 2.0 license
+
+use std::{
+    collections::HashMap,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        RwLock,
+    },
+    time::Duration,
+};
+
+use actix_web::rt::{self, time::sleep};
+use lazy_static::lazy_static;
+use serde::Deserialize;
+use sqlx::{mysql::MySqlRow, pool::PoolConnection, MySql, Row};
+
+use crate::{
+    config::Profile,
+    failover::{self, Host},
+    logic::get_pool,
+    req_res::Request,
+    statics::CONFIG,
+};
+
+// How often replication lag is read from the replicas
+const LAG_INTERVAL: Duration = Duration::from_secs(5);
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub enum Balance {
+    #[default]
+    RoundRobin,
+    // Fewest connections in use
+    LeastBusy,
+}
+
+// Where a read-only request may run
+#[derive(Debug, Clone, Copy, Default, PartialEq, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub enum Consistency {
+    #[default]
+    Eventual,
+    // Always the primary, to read what was just written
+    Primary,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(rename_all = "camelCase", deny_unknown_fields)]
+pub struct ReplicaSettings {
+    pub connection_strings: Vec<String>,
+    #[serde(default)]
+    pub balance: Balance,
+    // Replicas further behind, or not replicating, get no reads
+    pub max_lag_secs: Option<u64>,
+}
+
+impl ReplicaSettings {
+    pub fn validate(&self) -> Result<(), String> {
+        if self.connection_strings.is_empty() {
+            return Err("replicas: connectionStrings is empty".to_string());
+        }
+        Ok(())
+    }
+}
+
+lazy_static! {
+    // Seconds behind the primary by replica connection string, None when not replicating
+    static ref LAG: RwLock<HashMap<String, Option<u64>>> = RwLock::new(HashMap::new());
+}
+
+static NEXT: AtomicUsize = AtomicUsize::new(0);
+
+// Reads go to a replica unless the request asks for the primary or writes
+pub fn wants_replica(request: &Request) -> bool {
+    request.consistency != Consistency::Primary
+        && !request.transaction.iter().any(|item| item.statement.is_some())
+}
+
+fn usable(replica: &str, max_lag_secs: Option<u64>) -> bool {
+    match max_lag_secs {
+        // Unchecked replicas count as lagging, they get reads after the first check
+        Some(max) => matches!(LAG.read().unwrap().get(replica), Some(Some(lag)) if *lag <= max),
+        None => true,
+    }
+}
+
+// A connection for the request: on a replica that is up and keeps up, or on the primary
+// when there is none. Requests sent to a replica are made readOnly, so a query item that
+// writes fails there instead of writing to the wrong server. The host comes along so a
+// connection lost mid request can mark it down.
+pub async fn route(
+    profile: &Profile,
+    profile_name: &str,
+    request: &mut Request,
+) -> Result<(PoolConnection<MySql>, Option<Host>), actix_web::Error> {
+    let Some(replicas) = profile.replicas.as_ref().filter(|_| wants_replica(request)) else {
+        return failover::primary_host(profile, profile_name).await;
+    };
+    let candidates: Vec<(usize, &String)> = replicas
+        .connection_strings
+        .iter()
+        .enumerate()
+        .filter(|(_, replica)| !failover::is_down(replica) && usable(replica, replicas.max_lag_secs))
+        .collect();
+    if candidates.is_empty() {
+        log::debug!(profile = profile_name; "No replica keeps up, reading from the primary");
+        return failover::primary_host(profile, profile_name).await;
+    }
+    let (index, chosen) = match replicas.balance {
+        Balance::RoundRobin => candidates[NEXT.fetch_add(1, Ordering::Relaxed) % candidates.len()],
+        Balance::LeastBusy => {
+            let timeout = profile.failover.clone().unwrap_or_default().connect_timeout();
+            let mut least: Option<((usize, &String), u32)> = None;
+            for candidate in &candidates {
+                let Ok(Ok(pool)) = rt::time::timeout(timeout, get_pool(candidate.1)).await else {
+                    continue;
+                };
+                let busy = pool.size().saturating_sub(pool.num_idle() as u32);
+                if least.is_none_or(|(_, fewest)| busy < fewest) {
+                    least = Some((*candidate, busy));
+                }
+            }
+            match least {
+                Some((candidate, _)) => candidate,
+                None => return failover::primary_host(profile, profile_name).await,
+            }
+        }
+    };
+    // A replica that can't be reached doesn't fail reads the primary could serve
+    let settings = profile.failover.clone().unwrap_or_default();
+    let host = Host::new(chosen, profile_name, format!("replica {}", index + 1), &settings);
+    match failover::connect(chosen, profile_name, &settings).await {
+        Ok(conn) => {
+            request.read_only = true;
+            Ok((conn, Some(host)))
+        }
+        Err(e) => {
+            host.lost(&e.to_string());
+            failover::primary_host(profile, profile_name).await
+        }
+    }
+}
+
+fn seconds_behind(row: &MySqlRow) -> Option<u64> {
+    ["Seconds_Behind_Source", "Seconds_Behind_Master"]
+        .iter()
+        .find_map(|column| {
+            row.try_get::<Option<i64>, _>(*column)
+                .map(|lag| lag.map(|lag| lag.max(0) as u64))
+                .or_else(|_| row.try_get::<Option<u64>, _>(*column))
+                .ok()
+        })
+        .flatten()
+}
+
+async fn lag(replica: &str) -> Result<Option<u64>, sqlx::Error> {
+    let pool = get_pool(replica)
+        .await
+        .map_err(|e| sqlx::Error::Protocol(e.to_string()))?;
+    // SHOW REPLICA STATUS is MySQL 8.0.22+ and MariaDB 10.5.1+, older servers only know SLAVE
+    let row = match sqlx::query("SHOW REPLICA STATUS").fetch_optional(&pool).await {
+        Ok(row) => row,
+        Err(_) => sqlx::query("SHOW SLAVE STATUS").fetch_optional(&pool).await?,
+    };
+    Ok(row.as_ref().and_then(seconds_behind))
+}
+
+// Reads the lag of every replica of every profile with maxLagSecs, until the process ends
+pub fn monitor() {
+    rt::spawn(async {
+        loop {
+            let config = CONFIG.read().unwrap().clone();
+            for (name, profile) in &config.profiles {
+                let Some(replicas) = profile.replicas.as_ref().filter(|r| r.max_lag_secs.is_some())
+                else {
+                    continue;
+                };
+                // One replica that doesn't answer mustn't hold up the others
+                let timeout = profile.failover.clone().unwrap_or_default().connect_timeout();
+                for replica in &replicas.connection_strings {
+                    if failover::is_down(replica) {
+                        continue;
+                    }
+                    let lag = match rt::time::timeout(timeout, lag(replica)).await {
+                        Ok(Ok(lag)) => lag,
+                        Ok(Err(e)) => {
+                            log::warn!(profile = name.as_str(); "Could not read replica lag: {}", e);
+                            None
+                        }
+                        Err(_) => {
+                            log::warn!(
+                                profile = name.as_str();
+                                "Could not read replica lag: no answer within {}s", timeout.as_secs()
+                            );
+                            None
+                        }
+                    };
+                    LAG.write().unwrap().insert(replica.clone(), lag);
+                }
+            }
+            sleep(LAG_INTERVAL).await;
+        }
+    });
+}
@@ -28,19 +28,53 @@ use serde::{
 
 use serde_json::Value;
 
-#[derive(Debug, Deserialize)]
+use crate::{bulk::BulkMode, datetime::DateTimeSettings, params::EmptyList, replicas::Consistency};
+
+#[derive(Debug, Default, Deserialize)]
 pub struct ReqTransactionItem {
     pub query: Option<String>,
     pub statement: Option<String>,
     pub values: Option<Value>,
     #[serde(rename = "valuesBatch")]
     pub values_batch: Option<Vec<Value>>,
-    pub tag: Option<String>
+    //For queries with valuesBatch, return one result set with a _batchIndex column
+    #[serde(rename = "mergeBatch", default)]
+    pub merge_batch: bool,
+    //For INSERT statements with valuesBatch, insert many rows per statement
+    pub bulk: Option<BulkMode>,
+    pub tag: Option<String>,
+    //For queries with a profile, reuse the result set for this long
+    #[serde(rename = "cacheTtlSecs")]
+    pub cache_ttl_secs: Option<u64>,
+    //Tables the cached result reads, so it can be invalidated by table
+    #[serde(rename = "cacheTables", default)]
+    pub cache_tables: Vec<String>,
+    //For statements, drop cached results of these tables once committed
+    #[serde(rename = "invalidateTables", default)]
+    pub invalidate_tables: Vec<String>
 }
 
-#[derive(Debug, Deserialize)]
+#[derive(Debug, Default, Deserialize)]
 pub struct Request {
-    pub transaction: Vec<ReqTransactionItem>
+    pub transaction: Vec<ReqTransactionItem>,
+    //Bind plain json objects and arrays in values as json text
+    #[serde(rename = "jsonValues", default)]
+    pub json_values: bool,
+    //Whether an empty array bound to ?... is an error or matches nothing
+    #[serde(rename = "emptyList", default)]
+    pub empty_list: EmptyList,
+    //Overrides the datetime settings of the profile for this request
+    #[serde(default)]
+    pub datetime: DateTimeSettings,
+    //Add elapsedMs, the wall-clock time of the item, to every result
+    #[serde(default)]
+    pub timing: bool,
+    //Marks the transaction as reads only, statement items are refused
+    #[serde(rename = "readOnly", default)]
+    pub read_only: bool,
+    //"primary" keeps a read-only transaction off the replicas
+    #[serde(default)]
+    pub consistency: Consistency
 }
 
 #[derive(Debug)]
@@ -57,50 +91,88 @@ pub enum ResponseEnum {
     Error{tag: Option<String>, error: Box<dyn std::error::Error>}
 }
 
-impl Serialize for ResponseEnum {
-    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+impl ResponseEnum {
+    fn serialize_timed<S>(&self, elapsed_ms: Option<f64>, serializer: S) -> Result<S::Ok, S::Error>
     where
         S: Serializer,
     {
+        let mut map = serializer.serialize_map(None)?;
         match self {
-            ResponseEnum::ResponseItem{ tag, response_item} => match response_item {
-                ResponseItem::QuerySuccess{result_set} => {
-                    let mut map = serializer.serialize_map(Some(2))?;
-                    if let Some(tag) = tag {
-                        map.serialize_entry("tag", tag)?;
-                    }
-                    map.serialize_entry("success", "true")?;
-                    map.serialize_entry("resultsSet", result_set)?;
-                    map.end()
+            ResponseEnum::ResponseItem{ tag, response_item} => {
+                if let Some(tag) = tag {
+                    map.serialize_entry("tag", tag)?;
                 }
-                ResponseItem::StatementSuccess{rows_affected, last_insert_id} => {
-                    let mut map = serializer.serialize_map(Some(2))?;
-                    if let Some(tag) = tag {
-                        map.serialize_entry("tag", tag)?;
+                map.serialize_entry("success", "true")?;
+                match response_item {
+                    ResponseItem::QuerySuccess{result_set} => {
+                        map.serialize_entry("resultsSet", result_set)?;
+                    }
+                    ResponseItem::StatementSuccess{rows_affected, last_insert_id} => {
+                        map.serialize_entry("rowsAffected", rows_affected)?;
+                        map.serialize_entry("lastInsertId", last_insert_id)?;
                     }
-                    map.serialize_entry("success", "true")?;
-                    map.serialize_entry("rowsAffected", rows_affected)?;
-                    map.serialize_entry("lastInsertId", last_insert_id)?;
-                    map.end()
                 }
             },
             ResponseEnum::Error{tag, error} => {
-                let mut map = serializer.serialize_map(Some(2))?;
                 if let Some(tag) = tag {
                     map.serialize_entry("tag", tag)?;
                 }
                 map.serialize_entry("success", "false")?;
                 map.serialize_entry("error", &error.to_string())?;
-                map.end()
             }
         }
+        if let Some(elapsed_ms) = elapsed_ms {
+            map.serialize_entry("elapsedMs", &elapsed_ms)?;
+        }
+        map.end()
+    }
+}
+
+impl Serialize for ResponseEnum {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: Serializer,
+    {
+        self.serialize_timed(None, serializer)
+    }
+}
+
+struct TimedResult<'a>(&'a ResponseEnum, Option<f64>);
+
+impl Serialize for TimedResult<'_> {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: Serializer,
+    {
+        self.0.serialize_timed(self.1, serializer)
     }
 }
 
-#[derive(Debug, Serialize)]
+#[derive(Debug)]
 pub struct Response {
-    #[serde(skip_serializing_if = "Option::is_none")]
     pub results: Option<Vec<ResponseEnum>>,
+    //Time taken by the item each result came from, only set when the request asked for timing
+    pub elapsed_ms: Option<Vec<f64>>,
+}
+
+impl Serialize for Response {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: Serializer,
+    {
+        let mut map = serializer.serialize_map(None)?;
+        if let Some(results) = &self.results {
+            let timed: Vec<TimedResult> = results
+                .iter()
+                .enumerate()
+                .map(|(i, result)| {
+                    TimedResult(result, self.elapsed_ms.as_ref().and_then(|e| e.get(i).copied()))
+                })
+                .collect();
+            map.serialize_entry("results", &timed)?;
+        }
+        map.end()
+    }
 }
 
 impl Responder for Response {
@@ -112,20 +184,20 @@ impl Responder for Response {
                 .iter()
                 .any(|x| matches!(x, ResponseEnum::Error{..}))
             {
-                return HttpResponse::InternalServerError()
+                HttpResponse::InternalServerError()
                     .content_type(ContentType::json())
-                    .body(serde_json::to_string(&self).unwrap());
+                    .body(serde_json::to_string(&self).unwrap())
             } else {
-                return HttpResponse::Ok()
+                HttpResponse::Ok()
                     .status(StatusCode::from_u16(200).unwrap())
                     .content_type(ContentType::json())
-                    .body(serde_json::to_string(&self).unwrap());
+                    .body(serde_json::to_string(&self).unwrap())
             }
         } else {
             // No results should not be possible
-            return HttpResponse::InternalServerError()
+            HttpResponse::InternalServerError()
                 .content_type(ContentType::json())
-                .body(serde_json::to_string(&self).unwrap());
+                .body(serde_json::to_string(&self).unwrap())
         }
     }
 }
\ No newline at end of file
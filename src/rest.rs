@@ -0,0 +1,485 @@
+// Original copyright notice
+// Copyright (c) 2023-, Germano Rizzo <oss /AT/ germanorizzo /DOT/ it>
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+// This work was inspired by sqliterg by Germano Rizzo and heavily modified to suite mysql.
+// Modifications c) 2023-, Nexon Asia Pacific also under apache 2.0 license
+
+use std::{
+    collections::{BTreeMap, HashMap},
+    sync::{Arc, RwLock},
+    time::{Duration, Instant},
+};
+
+use actix_web::{error, web, HttpRequest, HttpResponse};
+use lazy_static::lazy_static;
+use percent_encoding::percent_decode_str;
+use serde::Deserialize;
+use serde_json::{json, Map, Value};
+
+use crate::{
+    audit, auth,
+    config::{Config, Profile},
+    failover,
+    logic::run_item,
+    metrics,
+    req_res::{ReqTransactionItem, ResponseItem},
+    schema::{self, Table},
+    statics::CONFIG,
+};
+
+// Anyone, including anonymous callers
+const ANY_ROLE: &str = "*";
+const DEFAULT_LIMIT: u64 = 100;
+// Columns are looked up again after this, so added columns show up without a reload
+const TABLE_TTL: Duration = Duration::from_secs(60);
+// Query string keys that aren't column filters
+const RESERVED: &[&str] = &["limit", "offset", "order"];
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(rename_all = "camelCase", deny_unknown_fields)]
+pub struct RestSettings {
+    // Only these tables get routes
+    pub tables: BTreeMap<String, TableAccess>,
+    // Most rows a list may return, whatever limit asks for
+    #[serde(default = "default_max_limit")]
+    pub max_limit: u64,
+}
+
+fn default_max_limit() -> u64 {
+    1000
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(rename_all = "camelCase", deny_unknown_fields)]
+pub struct TableAccess {
+    // Roles that may GET, "*" for anyone
+    #[serde(default = "default_read")]
+    pub read: Vec<String>,
+    // Roles that may POST, PATCH and DELETE, nobody by default
+    #[serde(default)]
+    pub write: Vec<String>,
+}
+
+fn default_read() -> Vec<String> {
+    vec![ANY_ROLE.to_string()]
+}
+
+impl TableAccess {
+    pub fn allows(&self, write: bool, roles: &[String]) -> bool {
+        allowed(if write { &self.write } else { &self.read }, roles)
+    }
+}
+
+// Connection URL and table name
+type TableKey = (String, String);
+
+lazy_static! {
+    static ref TABLES: RwLock<HashMap<TableKey, (Instant, Arc<Table>)>> =
+        RwLock::new(HashMap::new());
+}
+
+fn quote(identifier: &str) -> String {
+    format!("`{}`", identifier.replace('`', "``"))
+}
+
+// What a request works on once the table and the caller's permission are checked
+struct Target {
+    config: Arc<Config>,
+    profile_name: String,
+    table: Arc<Table>,
+}
+
+impl Target {
+    async fn resolve(
+        req: &HttpRequest,
+        profile_name: &str,
+        table: &str,
+        write: bool,
+    ) -> Result<Target, actix_web::Error> {
+        let config = CONFIG.read().unwrap().clone();
+        let profile = config
+            .profiles
+            .get(profile_name)
+            .ok_or_else(|| error::ErrorNotFound(format!("unknown profile '{}'", profile_name)))?;
+        // Tables that aren't listed look the same as tables that don't exist
+        let access = profile
+            .rest
+            .as_ref()
+            .and_then(|rest| rest.tables.get(table))
+            .ok_or_else(|| error::ErrorNotFound(format!("unknown table '{}'", table)))?;
+        let principal = auth::authenticated(req);
+        if !access.allows(write, roles(&config, principal.as_deref())) {
+            return Err(error::ErrorForbidden(format!(
+                "'{}' may not {} '{}'",
+                principal.as_deref().unwrap_or(audit::ANONYMOUS),
+                if write { "write" } else { "read" },
+                table
+            )));
+        }
+        let table = describe(profile, profile_name, table).await?;
+        Ok(Target {
+            profile_name: profile_name.to_string(),
+            table,
+            config,
+        })
+    }
+
+    fn max_limit(&self) -> u64 {
+        self.config.profiles[&self.profile_name]
+            .rest
+            .as_ref()
+            .map_or(DEFAULT_LIMIT, |rest| rest.max_limit)
+    }
+
+    fn column(&self, name: &str) -> Result<String, actix_web::Error> {
+        if self.table.columns.iter().any(|c| c.name == name) {
+            Ok(quote(name))
+        } else {
+            Err(error::ErrorBadRequest(format!("unknown column '{}'", name)))
+        }
+    }
+
+    async fn run(
+        &self,
+        req: &HttpRequest,
+        item: ReqTransactionItem,
+    ) -> Result<ResponseItem, actix_web::Error> {
+        run_item(req, &self.config, &self.profile_name, item).await
+    }
+}
+
+// The last path segment as sent, the router has already decoded %2C in the extracted one
+fn raw_pk(req: &HttpRequest) -> &str {
+    req.uri().path().rsplit('/').next().unwrap_or_default()
+}
+
+// Drops the described tables of a connection, for profiles a reload rebuilt or removed
+pub fn forget_tables(connection_url: &str) {
+    TABLES
+        .write()
+        .unwrap()
+        .retain(|(url, _), _| url != connection_url);
+}
+
+// Roles given to an authenticated principal in the config, everyone also counts for "*"
+pub fn roles<'a>(config: &'a Config, principal: Option<&str>) -> &'a [String] {
+    principal
+        .and_then(|principal| config.roles.get(principal))
+        .map_or(&[], Vec::as_slice)
+}
+
+pub fn allowed(allowed: &[String], roles: &[String]) -> bool {
+    allowed
+        .iter()
+        .any(|role| role == ANY_ROLE || roles.contains(role))
+}
+
+async fn describe(
+    profile: &Profile,
+    profile_name: &str,
+    table: &str,
+) -> Result<Arc<Table>, actix_web::Error> {
+    let url = profile.connection_url();
+    let key = (url, table.to_string());
+    if let Some((at, table)) = TABLES.read().unwrap().get(&key) {
+        if at.elapsed() < TABLE_TTL {
+            return Ok(table.clone());
+        }
+    }
+    let mut conn = failover::primary(profile, profile_name).await?;
+    let described = schema::introspect(&mut conn, None, Some(table))
+        .await
+        .map_err(|e| {
+            metrics::observe_error(profile_name, metrics::error_category(&e));
+            error::ErrorInternalServerError(e.to_string())
+        })?;
+    let found = described
+        .tables
+        .into_iter()
+        .next()
+        .map(Arc::new)
+        .ok_or_else(|| error::ErrorNotFound(format!("unknown table '{}'", table)))?;
+    TABLES
+        .write()
+        .unwrap()
+        .insert(key, (Instant::now(), found.clone()));
+    Ok(found)
+}
+
+// Composite keys are comma separated, in the order of the key's columns. pk is the raw
+// path segment, each value is decoded after splitting so %2C can stand for a comma.
+pub fn key(table: &Table, pk: &str) -> Result<(String, Vec<Value>), actix_web::Error> {
+    let columns = &table.primary_key;
+    if columns.is_empty() {
+        return Err(error::ErrorNotFound(format!(
+            "'{}' has no primary key",
+            table.name
+        )));
+    }
+    let values = if columns.len() == 1 {
+        vec![pk]
+    } else {
+        pk.split(',').collect()
+    };
+    let values = values
+        .into_iter()
+        .map(|value| {
+            percent_decode_str(value)
+                .decode_utf8()
+                .map(|value| Value::from(value.into_owned()))
+                .map_err(|_| error::ErrorBadRequest("the key isn't valid UTF-8"))
+        })
+        .collect::<Result<Vec<_>, _>>()?;
+    if values.len() != columns.len() {
+        return Err(error::ErrorBadRequest(format!(
+            "the key of '{}' has {} column(s)",
+            table.name,
+            columns.len()
+        )));
+    }
+    let condition = columns
+        .iter()
+        .map(|c| format!("{} = ?", quote(c)))
+        .collect::<Vec<_>>()
+        .join(" AND ");
+    Ok((condition, values))
+}
+
+// ?col=val filters on equality, order=name,-id sorts (descending with -), limit and
+// offset page
+pub fn select(
+    table: &str,
+    query: &BTreeMap<String, String>,
+    column: impl Fn(&str) -> Result<String, actix_web::Error>,
+    max_limit: u64,
+) -> Result<(String, Vec<Value>), actix_web::Error> {
+    let mut sql = format!("SELECT * FROM {}", quote(table));
+    let mut values = vec![];
+    let mut conditions = vec![];
+    for (key, value) in query
+        .iter()
+        .filter(|(key, _)| !RESERVED.contains(&key.as_str()))
+    {
+        conditions.push(format!("{} = ?", column(key)?));
+        values.push(Value::from(value.as_str()));
+    }
+    if !conditions.is_empty() {
+        sql.push_str(" WHERE ");
+        sql.push_str(&conditions.join(" AND "));
+    }
+    if let Some(order) = query.get("order").filter(|o| !o.is_empty()) {
+        let mut terms = vec![];
+        for term in order.split(',') {
+            terms.push(match term.strip_prefix('-') {
+                Some(name) => format!("{} DESC", column(name)?),
+                None => format!("{} ASC", column(term)?),
+            });
+        }
+        sql.push_str(" ORDER BY ");
+        sql.push_str(&terms.join(", "));
+    }
+    let number = |key: &str, default: u64| -> Result<u64, actix_web::Error> {
+        query.get(key).map_or(Ok(default), |v| {
+            v.parse()
+                .map_err(|_| error::ErrorBadRequest(format!("{} must be a number", key)))
+        })
+    };
+    sql.push_str(" LIMIT ? OFFSET ?");
+    values.push(Value::from(
+        number("limit", DEFAULT_LIMIT.min(max_limit))?.min(max_limit),
+    ));
+    values.push(Value::from(number("offset", 0)?));
+    Ok((sql, values))
+}
+
+fn rows(item: ResponseItem) -> Vec<Value> {
+    match item {
+        ResponseItem::QuerySuccess { result_set } => result_set,
+        ResponseItem::StatementSuccess { .. } => vec![],
+    }
+}
+
+fn counts(item: &ResponseItem) -> (u64, u64) {
+    match item {
+        ResponseItem::StatementSuccess {
+            rows_affected,
+            last_insert_id,
+        } => (*rows_affected, *last_insert_id),
+        ResponseItem::QuerySuccess { .. } => (0, 0),
+    }
+}
+
+fn query(sql: String, values: Vec<Value>) -> ReqTransactionItem {
+    ReqTransactionItem {
+        query: Some(sql),
+        values: Some(Value::Array(values)),
+        ..Default::default()
+    }
+}
+
+// Writes drop cached results tagged with the table
+fn statement(table: &str, sql: String, values: Vec<Value>) -> ReqTransactionItem {
+    ReqTransactionItem {
+        statement: Some(sql),
+        values: Some(Value::Array(values)),
+        invalidate_tables: vec![table.to_string()],
+        ..Default::default()
+    }
+}
+
+// GET /db/{profile}/tables/{table}
+pub async fn list(
+    req: HttpRequest,
+    path: web::Path<(String, String)>,
+    params: web::Query<BTreeMap<String, String>>,
+) -> Result<HttpResponse, actix_web::Error> {
+    let (profile_name, table) = path.into_inner();
+    let target = Target::resolve(&req, &profile_name, &table, false).await?;
+    let (sql, values) = select(
+        &target.table.name,
+        &params,
+        |c| target.column(c),
+        target.max_limit(),
+    )?;
+    let item = target.run(&req, query(sql, values)).await?;
+    Ok(HttpResponse::Ok().json(rows(item)))
+}
+
+// GET /db/{profile}/tables/{table}/{pk}
+pub async fn read(
+    req: HttpRequest,
+    path: web::Path<(String, String, String)>,
+) -> Result<HttpResponse, actix_web::Error> {
+    let (profile_name, table, _) = path.into_inner();
+    let target = Target::resolve(&req, &profile_name, &table, false).await?;
+    let (condition, values) = key(&target.table, raw_pk(&req))?;
+    let sql = format!(
+        "SELECT * FROM {} WHERE {} LIMIT 1",
+        quote(&target.table.name),
+        condition
+    );
+    let item = target.run(&req, query(sql, values)).await?;
+    match rows(item).into_iter().next() {
+        Some(row) => Ok(HttpResponse::Ok().json(row)),
+        None => Err(error::ErrorNotFound("no such row")),
+    }
+}
+
+fn assignments(
+    target: &Target,
+    body: &Map<String, Value>,
+) -> Result<(Vec<String>, Vec<Value>), actix_web::Error> {
+    if body.is_empty() {
+        return Err(error::ErrorBadRequest("the body has no columns"));
+    }
+    let mut columns = vec![];
+    for name in body.keys() {
+        columns.push(target.column(name)?);
+    }
+    Ok((columns, body.values().cloned().collect()))
+}
+
+// POST /db/{profile}/tables/{table} with the new row as a JSON object
+pub async fn create(
+    req: HttpRequest,
+    path: web::Path<(String, String)>,
+    body: web::Json<Map<String, Value>>,
+) -> Result<HttpResponse, actix_web::Error> {
+    let (profile_name, table) = path.into_inner();
+    let target = Target::resolve(&req, &profile_name, &table, true).await?;
+    let (columns, values) = assignments(&target, &body)?;
+    let sql = format!(
+        "INSERT INTO {} ({}) VALUES ({})",
+        quote(&target.table.name),
+        columns.join(", "),
+        vec!["?"; columns.len()].join(", ")
+    );
+    let (rows_affected, last_insert_id) = counts(
+        &target
+            .run(&req, statement(&target.table.name, sql, values))
+            .await?,
+    );
+    Ok(HttpResponse::Created()
+        .json(json!({"rowsAffected": rows_affected, "lastInsertId": last_insert_id})))
+}
+
+// PATCH /db/{profile}/tables/{table}/{pk} with the columns to change
+pub async fn update(
+    req: HttpRequest,
+    path: web::Path<(String, String, String)>,
+    body: web::Json<Map<String, Value>>,
+) -> Result<HttpResponse, actix_web::Error> {
+    let (profile_name, table, _) = path.into_inner();
+    let target = Target::resolve(&req, &profile_name, &table, true).await?;
+    let (columns, mut values) = assignments(&target, &body)?;
+    let (condition, key) = key(&target.table, raw_pk(&req))?;
+    values.extend(key);
+    let sql = format!(
+        "UPDATE {} SET {} WHERE {}",
+        quote(&target.table.name),
+        columns
+            .iter()
+            .map(|c| format!("{} = ?", c))
+            .collect::<Vec<_>>()
+            .join(", "),
+        condition
+    );
+    // Counts matched rows, so an update that changes nothing isn't a 404
+    match counts(
+        &target
+            .run(&req, statement(&target.table.name, sql, values))
+            .await?,
+    ) {
+        (0, _) => Err(error::ErrorNotFound("no such row")),
+        (rows_affected, _) => Ok(HttpResponse::Ok().json(json!({"rowsAffected": rows_affected}))),
+    }
+}
+
+// DELETE /db/{profile}/tables/{table}/{pk}
+pub async fn delete(
+    req: HttpRequest,
+    path: web::Path<(String, String, String)>,
+) -> Result<HttpResponse, actix_web::Error> {
+    let (profile_name, table, _) = path.into_inner();
+    let target = Target::resolve(&req, &profile_name, &table, true).await?;
+    let (condition, values) = key(&target.table, raw_pk(&req))?;
+    let sql = format!(
+        "DELETE FROM {} WHERE {}",
+        quote(&target.table.name),
+        condition
+    );
+    match counts(
+        &target
+            .run(&req, statement(&target.table.name, sql, values))
+            .await?,
+    ) {
+        (0, _) => Err(error::ErrorNotFound("no such row")),
+        (rows_affected, _) => Ok(HttpResponse::Ok().json(json!({"rowsAffected": rows_affected}))),
+    }
+}
+
+pub fn configure(cfg: &mut web::ServiceConfig) {
+    cfg.service(
+        web::resource("/db/{profile}/tables/{table}")
+            .route(web::get().to(list))
+            .route(web::post().to(create)),
+    )
+    .service(
+        web::resource("/db/{profile}/tables/{table}/{pk}")
+            .route(web::get().to(read))
+            .route(web::patch().to(update))
+            .route(web::delete().to(delete)),
+    );
+}
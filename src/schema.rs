@@ -0,0 +1,257 @@
+// Original copyright notice
+// Copyright (c) 2023-, Germano Rizzo <oss /AT/ germanorizzo /DOT/ it>
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+// This work was inspired by sqliterg by Germano Rizzo and heavily modified to suite mysql.
+// Modifications c) 2023-, Nexon Asia Pacific also under apache 2.0 license
+
+use actix_web::{error, web, HttpRequest, HttpResponse};
+use serde::{Deserialize, Serialize};
+use sqlx::{
+    mysql::{MySqlConnection, MySqlRow},
+    Row,
+};
+
+use crate::{
+    failover, http_cache, metrics,
+    statics::CONFIG,
+};
+
+// Text columns of information_schema are binary on some servers, so they are cast
+const TABLES: &str = "SELECT CAST(TABLE_NAME AS CHAR) AS name, CAST(TABLE_TYPE AS CHAR) AS kind, \
+    CAST(TABLE_COMMENT AS CHAR) AS comment \
+    FROM information_schema.TABLES WHERE TABLE_SCHEMA = ? AND (? IS NULL OR TABLE_NAME = ?) \
+    ORDER BY TABLE_NAME";
+
+const COLUMNS: &str = "SELECT CAST(TABLE_NAME AS CHAR) AS table_name, CAST(COLUMN_NAME AS CHAR) AS name, \
+    CAST(COLUMN_TYPE AS CHAR) AS column_type, CAST(DATA_TYPE AS CHAR) AS data_type, \
+    CAST(IS_NULLABLE = 'YES' AS SIGNED) AS nullable, CAST(COLUMN_DEFAULT AS CHAR) AS column_default, \
+    CAST(EXTRA AS CHAR) AS extra, CAST(COLUMN_COMMENT AS CHAR) AS comment \
+    FROM information_schema.COLUMNS WHERE TABLE_SCHEMA = ? AND (? IS NULL OR TABLE_NAME = ?) \
+    ORDER BY TABLE_NAME, ORDINAL_POSITION";
+
+const INDEXES: &str = "SELECT CAST(TABLE_NAME AS CHAR) AS table_name, CAST(INDEX_NAME AS CHAR) AS name, \
+    CAST(NON_UNIQUE = 0 AS SIGNED) AS is_unique, CAST(COLUMN_NAME AS CHAR) AS column_name \
+    FROM information_schema.STATISTICS WHERE TABLE_SCHEMA = ? AND (? IS NULL OR TABLE_NAME = ?) \
+    ORDER BY TABLE_NAME, INDEX_NAME, SEQ_IN_INDEX";
+
+const FOREIGN_KEYS: &str = "SELECT CAST(k.TABLE_NAME AS CHAR) AS table_name, \
+    CAST(k.CONSTRAINT_NAME AS CHAR) AS name, CAST(k.COLUMN_NAME AS CHAR) AS column_name, \
+    CAST(k.REFERENCED_TABLE_SCHEMA AS CHAR) AS referenced_schema, \
+    CAST(k.REFERENCED_TABLE_NAME AS CHAR) AS referenced_table, \
+    CAST(k.REFERENCED_COLUMN_NAME AS CHAR) AS referenced_column, \
+    CAST(r.UPDATE_RULE AS CHAR) AS on_update, CAST(r.DELETE_RULE AS CHAR) AS on_delete \
+    FROM information_schema.KEY_COLUMN_USAGE k \
+    JOIN information_schema.REFERENTIAL_CONSTRAINTS r \
+    ON r.CONSTRAINT_SCHEMA = k.CONSTRAINT_SCHEMA AND r.CONSTRAINT_NAME = k.CONSTRAINT_NAME \
+    AND r.TABLE_NAME = k.TABLE_NAME \
+    WHERE k.TABLE_SCHEMA = ? AND (? IS NULL OR k.TABLE_NAME = ?) AND k.REFERENCED_TABLE_NAME IS NOT NULL \
+    ORDER BY k.TABLE_NAME, k.CONSTRAINT_NAME, k.ORDINAL_POSITION";
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Schema {
+    pub schema: String,
+    pub tables: Vec<Table>,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Table {
+    pub name: String,
+    // BASE TABLE or VIEW
+    pub kind: String,
+    pub comment: String,
+    pub columns: Vec<Column>,
+    pub primary_key: Vec<String>,
+    pub indexes: Vec<Index>,
+    pub foreign_keys: Vec<ForeignKey>,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Column {
+    pub name: String,
+    // Full type like int(10) unsigned, dataType is just int
+    #[serde(rename = "type")]
+    pub column_type: String,
+    pub data_type: String,
+    pub nullable: bool,
+    // As the server reports it, MariaDB quotes string defaults
+    pub default: Option<String>,
+    pub extra: String,
+    pub comment: String,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Index {
+    pub name: String,
+    pub unique: bool,
+    pub columns: Vec<String>,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ForeignKey {
+    pub name: String,
+    pub columns: Vec<String>,
+    pub referenced_schema: String,
+    pub referenced_table: String,
+    pub referenced_columns: Vec<String>,
+    pub on_update: String,
+    pub on_delete: String,
+}
+
+impl Schema {
+    pub fn table(&self, name: &str) -> Option<&Table> {
+        self.tables.iter().find(|t| t.name == name)
+    }
+}
+
+fn text(row: &MySqlRow, column: &str) -> Result<String, sqlx::Error> {
+    Ok(row.try_get::<Option<String>, _>(column)?.unwrap_or_default())
+}
+
+async fn fetch(
+    conn: &mut MySqlConnection,
+    sql: &str,
+    schema: &str,
+    table: Option<&str>,
+) -> Result<Vec<MySqlRow>, sqlx::Error> {
+    sqlx::query(sql)
+        .bind(schema)
+        .bind(table)
+        .bind(table)
+        .fetch_all(conn)
+        .await
+}
+
+// Rows of tables created since the table list was read are skipped
+fn table_mut<'a>(tables: &'a mut [Table], name: &str) -> Option<&'a mut Table> {
+    tables.iter_mut().find(|t| t.name == name)
+}
+
+// Tables, views and their columns, keys and indexes, in a stable order. The schema
+// defaults to the connection's database.
+pub async fn introspect(
+    conn: &mut MySqlConnection,
+    schema: Option<&str>,
+    table: Option<&str>,
+) -> Result<Schema, sqlx::Error> {
+    let schema = match schema {
+        Some(schema) => schema.to_string(),
+        None => sqlx::query_scalar::<_, Option<String>>("SELECT DATABASE()")
+            .fetch_one(&mut *conn)
+            .await?
+            .ok_or_else(|| sqlx::Error::Protocol("no database selected, pass a schema".to_string()))?,
+    };
+
+    let mut tables = vec![];
+    for row in fetch(conn, TABLES, &schema, table).await? {
+        tables.push(Table {
+            name: text(&row, "name")?,
+            kind: text(&row, "kind")?,
+            comment: text(&row, "comment")?,
+            columns: vec![],
+            primary_key: vec![],
+            indexes: vec![],
+            foreign_keys: vec![],
+        });
+    }
+    for row in fetch(conn, COLUMNS, &schema, table).await? {
+        if let Some(t) = table_mut(&mut tables, &text(&row, "table_name")?) {
+            t.columns.push(Column {
+                name: text(&row, "name")?,
+                column_type: text(&row, "column_type")?,
+                data_type: text(&row, "data_type")?,
+                nullable: row.try_get::<i64, _>("nullable")? != 0,
+                default: row.try_get("column_default")?,
+                extra: text(&row, "extra")?,
+                comment: text(&row, "comment")?,
+            });
+        }
+    }
+    for row in fetch(conn, INDEXES, &schema, table).await? {
+        let Some(t) = table_mut(&mut tables, &text(&row, "table_name")?) else {
+            continue;
+        };
+        let name = text(&row, "name")?;
+        let column = text(&row, "column_name")?;
+        if name == "PRIMARY" {
+            t.primary_key.push(column);
+            continue;
+        }
+        match t.indexes.last_mut() {
+            Some(index) if index.name == name => index.columns.push(column),
+            _ => t.indexes.push(Index {
+                name,
+                unique: row.try_get::<i64, _>("is_unique")? != 0,
+                columns: vec![column],
+            }),
+        }
+    }
+    for row in fetch(conn, FOREIGN_KEYS, &schema, table).await? {
+        let Some(t) = table_mut(&mut tables, &text(&row, "table_name")?) else {
+            continue;
+        };
+        let name = text(&row, "name")?;
+        let column = text(&row, "column_name")?;
+        let referenced_column = text(&row, "referenced_column")?;
+        match t.foreign_keys.last_mut() {
+            Some(key) if key.name == name => {
+                key.columns.push(column);
+                key.referenced_columns.push(referenced_column);
+            }
+            _ => t.foreign_keys.push(ForeignKey {
+                name,
+                columns: vec![column],
+                referenced_schema: text(&row, "referenced_schema")?,
+                referenced_table: text(&row, "referenced_table")?,
+                referenced_columns: vec![referenced_column],
+                on_update: text(&row, "on_update")?,
+                on_delete: text(&row, "on_delete")?,
+            }),
+        }
+    }
+    Ok(Schema { schema, tables })
+}
+
+#[derive(Debug, Deserialize)]
+pub struct SchemaQuery {
+    pub schema: Option<String>,
+    pub table: Option<String>,
+}
+
+// GET /db/{profile}/schema, the ETag changes with the schema so generators can poll cheaply
+pub async fn handler(
+    req: HttpRequest,
+    profile_name: web::Path<String>,
+    query: web::Query<SchemaQuery>,
+) -> Result<HttpResponse, actix_web::Error> {
+    let config = CONFIG.read().unwrap().clone();
+    let profile = config
+        .profiles
+        .get(profile_name.as_str())
+        .ok_or_else(|| error::ErrorNotFound(format!("unknown profile '{}'", profile_name)))?;
+    let mut conn = failover::primary(profile, &profile_name).await?;
+    let schema = introspect(&mut conn, query.schema.as_deref(), query.table.as_deref())
+        .await
+        .map_err(|e| {
+            metrics::observe_error(&profile_name, metrics::error_category(&e));
+            error::ErrorInternalServerError(e.to_string())
+        })?;
+    let body = serde_json::to_vec(&schema).map_err(error::ErrorInternalServerError)?;
+    Ok(http_cache::respond(&req, body, None))
+}
@@ -0,0 +1,212 @@
+// Original copyright notice
+// Copyright (c) 2023-, Germano Rizzo <oss /AT/ germanorizzo /DOT/ it>
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+// This work was inspired by sqliterg by Germano Rizzo and heavily modified to suite mysql.
+// Modifications c) 2023-, Nexon Asia Pacific also under apache 2.0 license
+
+use std::{
+    future::{poll_fn, Future},
+    pin::pin,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        OnceLock,
+    },
+    task::Poll,
+    time::{Duration, Instant},
+};
+
+use actix_web::{
+    body::EitherBody,
+    dev::{Service, ServiceRequest, ServiceResponse, ServerHandle},
+    http::header,
+    rt::{self, time},
+    HttpResponse,
+};
+use tokio::sync::Notify;
+
+use crate::statics::CONNECTION_WATER_PARK;
+
+// How long requests still running at the drain deadline get to roll back, before the
+// servers drop them
+pub const ROLLBACK_GRACE: Duration = Duration::from_secs(5);
+
+// Set when the drain starts, nothing commits once it has passed
+static DEADLINE: OnceLock<Instant> = OnceLock::new();
+static IN_FLIGHT: AtomicUsize = AtomicUsize::new(0);
+// Woken once DEADLINE is set, and whenever the last running request finishes
+static STARTED: Notify = Notify::const_new();
+static IDLE: Notify = Notify::const_new();
+
+pub fn draining() -> bool {
+    DEADLINE.get().is_some()
+}
+
+pub fn expired() -> bool {
+    DEADLINE.get().is_some_and(|deadline| Instant::now() >= *deadline)
+}
+
+// Runs fut unless the drain deadline passes first, then it is dropped and None returned
+pub async fn before_deadline<F: Future>(fut: F) -> Option<F::Output> {
+    let mut fut = pin!(fut);
+    let mut deadline = pin!(async {
+        // Registered before DEADLINE is read, so a drain starting in between still wakes it
+        let mut started = pin!(STARTED.notified());
+        started.as_mut().enable();
+        let deadline = match DEADLINE.get() {
+            Some(deadline) => *deadline,
+            None => {
+                started.await;
+                *DEADLINE.get().unwrap()
+            }
+        };
+        time::sleep_until(deadline.into()).await
+    });
+    poll_fn(|cx| {
+        if let Poll::Ready(output) = fut.as_mut().poll(cx) {
+            Poll::Ready(Some(output))
+        } else if deadline.as_mut().poll(cx).is_ready() {
+            Poll::Ready(None)
+        } else {
+            Poll::Pending
+        }
+    })
+    .await
+}
+
+pub fn in_flight() -> usize {
+    IN_FLIGHT.load(Ordering::SeqCst)
+}
+
+struct InFlight;
+
+impl InFlight {
+    // Counted before checking, so a request can't slip in unseen as the drain starts
+    fn enter() -> Option<InFlight> {
+        IN_FLIGHT.fetch_add(1, Ordering::SeqCst);
+        let guard = InFlight;
+        (!draining()).then_some(guard)
+    }
+}
+
+impl Drop for InFlight {
+    fn drop(&mut self) {
+        if IN_FLIGHT.fetch_sub(1, Ordering::SeqCst) == 1 {
+            IDLE.notify_waiters();
+        }
+    }
+}
+
+// Counts running requests and answers new ones with 503 while draining. /healthz still
+// answers, the process is alive until the drain is over.
+pub fn middleware<S, B>(
+    req: ServiceRequest,
+    srv: &S,
+) -> impl Future<Output = Result<ServiceResponse<EitherBody<B>>, actix_web::Error>>
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = actix_web::Error>,
+{
+    let guard = InFlight::enter();
+    let next = if guard.is_some() || req.path() == "/healthz" {
+        Ok(srv.call(req))
+    } else {
+        Err(req.into_response(
+            HttpResponse::ServiceUnavailable()
+                .insert_header((header::CONNECTION, "close"))
+                .body("shutting down"),
+        ))
+    };
+    async move {
+        let _guard = guard;
+        match next {
+            Ok(fut) => fut.await.map(ServiceResponse::map_into_left_body),
+            Err(res) => Ok(res.map_into_right_body()),
+        }
+    }
+}
+
+// Waits for running requests until the deadline, gives the rest time to roll back, then
+// stops the servers and closes every pool
+pub async fn drain(servers: Vec<ServerHandle>, timeout: Duration) {
+    let deadline = *DEADLINE.get_or_init(|| Instant::now() + timeout);
+    STARTED.notify_waiters();
+    log::info!("Draining, {} request(s) in flight", in_flight());
+    settle(deadline).await;
+    if in_flight() > 0 {
+        log::warn!(
+            "{} request(s) still running at the drain deadline, rolling them back",
+            in_flight()
+        );
+        settle(Instant::now() + ROLLBACK_GRACE).await;
+    }
+    for server in servers {
+        server.stop(true).await;
+    }
+    let pools: Vec<_> = CONNECTION_WATER_PARK
+        .write()
+        .unwrap()
+        .drain()
+        .map(|(_, pool)| pool)
+        .collect();
+    for pool in &pools {
+        pool.close().await;
+    }
+    log::info!("Closed {} pool(s)", pools.len());
+}
+
+// Waits until no request is running, or until the given time
+async fn settle(until: Instant) {
+    loop {
+        let mut idle = pin!(IDLE.notified());
+        idle.as_mut().enable();
+        if in_flight() == 0 {
+            return;
+        }
+        let left = until.saturating_duration_since(Instant::now());
+        if time::timeout(left, idle).await.is_err() {
+            return;
+        }
+    }
+}
+
+// The servers' own signal handling is disabled, SIGTERM and SIGINT start the drain instead
+#[cfg(unix)]
+pub fn drain_on_signal(servers: Vec<ServerHandle>, timeout: Duration) -> std::io::Result<()> {
+    use actix_web::rt::signal::unix::{signal, SignalKind};
+
+    let mut terminate = signal(SignalKind::terminate())?;
+    let mut interrupt = signal(SignalKind::interrupt())?;
+    rt::spawn(async move {
+        poll_fn(|cx| {
+            if terminate.poll_recv(cx).is_ready() || interrupt.poll_recv(cx).is_ready() {
+                Poll::Ready(())
+            } else {
+                Poll::Pending
+            }
+        })
+        .await;
+        drain(servers, timeout).await;
+    });
+    Ok(())
+}
+
+#[cfg(not(unix))]
+pub fn drain_on_signal(servers: Vec<ServerHandle>, timeout: Duration) -> std::io::Result<()> {
+    rt::spawn(async move {
+        if rt::signal::ctrl_c().await.is_ok() {
+            drain(servers, timeout).await;
+        }
+    });
+    Ok(())
+}
@@ -0,0 +1,98 @@
+// Original copyright notice
+// Copyright (c) 2023-, Germano Rizzo <oss /AT/ germanorizzo /DOT/ it>
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+// This work was inspired by sqliterg by Germano Rizzo and heavily modified to suite mysql.
+// Modifications c) 2023-, Nexon Asia Pacific also under apache 2.0 license
+
+use std::path::{Path, PathBuf};
+
+use actix_files::{Files, NamedFile};
+use actix_web::{
+    dev::{fn_service, ServiceRequest, ServiceResponse},
+    web,
+};
+use serde::Deserialize;
+
+// Paths served by the gateway itself, which a static mount must stay out of
+pub const API_PATHS: &[&str] = &["/healthz", "/readyz", "/metrics", "/console", "/admin", "/db"];
+
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(rename_all = "camelCase", deny_unknown_fields)]
+pub struct StaticFilesSettings {
+    pub dir: String,
+    // Where the directory shows up, "/" is fine as the API routes are matched first
+    #[serde(default = "default_mount_path")]
+    pub mount_path: String,
+    #[serde(default = "default_index")]
+    pub index: String,
+    // Answer unknown paths under the mount with the index, for client side routing
+    #[serde(default)]
+    pub spa_fallback: bool,
+}
+
+fn default_mount_path() -> String {
+    "/ui".to_string()
+}
+
+fn default_index() -> String {
+    "index.html".to_string()
+}
+
+impl StaticFilesSettings {
+    pub fn validate(&self) -> Result<(), String> {
+        let mount = self.mount_path.trim_end_matches('/');
+        if !self.mount_path.starts_with('/') {
+            return Err(format!("staticFiles: mountPath '{}' must start with /", self.mount_path));
+        }
+        if let Some(api) = API_PATHS
+            .iter()
+            .find(|api| mount == **api || mount.starts_with(&format!("{}/", api)))
+        {
+            return Err(format!("staticFiles: mountPath '{}' collides with {}", self.mount_path, api));
+        }
+        if !self.dir().is_dir() {
+            return Err(format!("staticFiles: '{}' is not a directory", self.dir));
+        }
+        Ok(())
+    }
+
+    fn dir(&self) -> PathBuf {
+        PathBuf::from(shellexpand::tilde(&self.dir).into_owned())
+    }
+}
+
+// Registered after the API routes so they always win
+pub fn configure(cfg: &mut web::ServiceConfig, settings: Option<&StaticFilesSettings>) {
+    let Some(settings) = settings else {
+        return;
+    };
+    let dir = settings.dir();
+    let mut files = Files::new(&settings.mount_path, &dir).index_file(settings.index.clone());
+    if settings.spa_fallback {
+        let index = dir.join(&settings.index);
+        files = files.default_handler(fn_service(move |req: ServiceRequest| {
+            let index = index.clone();
+            async move { serve_index(req, &index).await }
+        }));
+    }
+    cfg.service(files);
+}
+
+async fn serve_index(req: ServiceRequest, index: &Path) -> Result<ServiceResponse, actix_web::Error> {
+    let (req, _) = req.into_parts();
+    let file = NamedFile::open_async(index).await?;
+    let res = file.into_response(&req);
+    Ok(ServiceResponse::new(req, res))
+}
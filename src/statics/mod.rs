@@ -16,16 +16,19 @@
 // This work was inspired by sqliterg by Germano Rizzo and heavily modified to suite mysql.
 // Modifications c) 2023-, Nexon Asia Pacific also under apache 2.0 license
 
-use std::{collections::HashMap, sync::RwLock};
+use std::{collections::HashMap, sync::{Arc, RwLock}};
 
 use lazy_static::lazy_static;
 use sqlx::{Pool, MySql};
+
+use crate::config::Config;
 lazy_static! {
     #[derive(Debug)]
     pub static ref CONNECTION_WATER_PARK: RwLock<HashMap<String, Pool<MySql>>> =
     {
         let map:HashMap<String, Pool<MySql>> = HashMap::new();
-        let lock = RwLock::new(map);
-        lock
+        RwLock::new(map)
     };
+
+    pub static ref CONFIG: RwLock<Arc<Config>> = RwLock::new(Arc::new(Config::default()));
 }
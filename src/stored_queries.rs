@@ -0,0 +1,197 @@
+// Original copyright notice
+// Copyright (c) 2023-, Germano Rizzo <oss /AT/ germanorizzo /DOT/ it>
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+// This work was inspired by sqliterg by Germano Rizzo and heavily modified to suite mysql.
+// Modifications c) 2023-, Nexon Asia Pacific also under apache 2.0 license
+
+use std::collections::BTreeMap;
+
+use actix_web::{error, web, HttpRequest, HttpResponse};
+use serde::Deserialize;
+use serde_json::{json, Value};
+
+use crate::{
+    audit, auth, http_cache,
+    logic::run_item,
+    params::{keywords, placeholders, redact_literals, PARAMETER_TYPES},
+    req_res::{ReqTransactionItem, ResponseItem},
+    rest,
+    statics::CONFIG,
+};
+
+// First keywords of statements that only read
+const READ_ONLY: &[&str] = &["SELECT", "WITH", "SHOW", "DESCRIBE", "DESC", "EXPLAIN"];
+// Anywhere in the statement these write or lock, e.g. INTO OUTFILE, FOR UPDATE or a WITH
+// ending in DELETE
+const WRITES: &[&str] = &["INSERT", "UPDATE", "DELETE", "REPLACE", "INTO", "LOCK", "SHARE"];
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(rename_all = "camelCase", deny_unknown_fields)]
+pub struct StoredQuery {
+    // A single read-only statement with ? placeholders, ?... for list parameters
+    pub sql: String,
+    // In placeholder order
+    #[serde(default)]
+    pub params: Vec<QueryParam>,
+    // Roles that may run it, "*" for anyone
+    #[serde(default = "default_roles")]
+    pub roles: Vec<String>,
+    // Lets browsers and caches reuse the response, otherwise they revalidate with the ETag
+    pub max_age_secs: Option<u64>,
+    // Keep results in the gateway's result cache
+    pub cache: Option<QueryCache>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(rename_all = "camelCase", deny_unknown_fields)]
+pub struct QueryCache {
+    pub ttl_secs: u64,
+    // Tables read, so writes to them can invalidate the results
+    #[serde(default)]
+    pub tables: Vec<String>,
+}
+
+fn default_roles() -> Vec<String> {
+    vec!["*".to_string()]
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(rename_all = "camelCase", deny_unknown_fields)]
+pub struct QueryParam {
+    pub name: String,
+    // Any type of typed values, e.g. int, decimal or datetime
+    #[serde(rename = "type", default = "default_type")]
+    pub kind: String,
+    // Comma separated values bound to a ?... placeholder
+    #[serde(default)]
+    pub list: bool,
+    // Used when the parameter is left out, otherwise it is required
+    pub default: Option<String>,
+}
+
+fn default_type() -> String {
+    "string".to_string()
+}
+
+impl StoredQuery {
+    pub fn validate(&self) -> Result<(), String> {
+        // Literals and comments are blanked first, so neither can hide a second statement
+        let sql = redact_literals(&self.sql);
+        let sql = sql.trim().trim_end_matches(';');
+        if sql.contains(';') {
+            return Err("only a single statement is allowed".to_string());
+        }
+        let keyword = sql
+            .split(|c: char| !c.is_ascii_alphabetic())
+            .next()
+            .unwrap_or_default()
+            .to_ascii_uppercase();
+        if !READ_ONLY.contains(&keyword.as_str()) {
+            return Err(format!("only read-only statements are allowed, not {}", keyword));
+        }
+        if let Some(word) = keywords(&self.sql).into_iter().find(|w| WRITES.contains(&w.as_str())) {
+            return Err(format!("only read-only statements are allowed, found {}", word));
+        }
+        let placeholders = placeholders(&self.sql);
+        if placeholders.len() != self.params.len() {
+            return Err(format!(
+                "{} parameter(s) declared for {} placeholder(s)",
+                self.params.len(),
+                placeholders.len()
+            ));
+        }
+        for (param, list) in self.params.iter().zip(placeholders) {
+            if !PARAMETER_TYPES.contains(&param.kind.to_ascii_lowercase().as_str()) {
+                return Err(format!("parameter '{}' has unknown type '{}'", param.name, param.kind));
+            }
+            if param.list != list {
+                return Err(format!(
+                    "parameter '{}' needs list: {} to match its placeholder",
+                    param.name, list
+                ));
+            }
+        }
+        Ok(())
+    }
+
+    // Query string values as typed values, so they are parsed as the declared type
+    pub fn values(&self, query: &BTreeMap<String, String>) -> Result<Vec<Value>, String> {
+        if let Some(unknown) = query
+            .keys()
+            .find(|key| !self.params.iter().any(|p| &p.name == *key))
+        {
+            return Err(format!("unknown parameter '{}'", unknown));
+        }
+        let mut values = vec![];
+        for param in &self.params {
+            let text = query
+                .get(&param.name)
+                .or(param.default.as_ref())
+                .ok_or_else(|| format!("missing parameter '{}'", param.name))?;
+            let typed = |value: &str| json!({"type": param.kind, "value": value});
+            values.push(if param.list {
+                Value::Array(
+                    text.split(',')
+                        .filter(|v| !v.is_empty())
+                        .map(typed)
+                        .collect(),
+                )
+            } else {
+                typed(text)
+            });
+        }
+        Ok(values)
+    }
+}
+
+// GET /db/{profile}/q/{name}?param=value
+pub async fn handler(
+    req: HttpRequest,
+    path: web::Path<(String, String)>,
+    query: web::Query<BTreeMap<String, String>>,
+) -> Result<HttpResponse, actix_web::Error> {
+    let (profile_name, name) = path.into_inner();
+    let config = CONFIG.read().unwrap().clone();
+    let stored = config
+        .profiles
+        .get(&profile_name)
+        .ok_or_else(|| error::ErrorNotFound(format!("unknown profile '{}'", profile_name)))?
+        .queries
+        .get(&name)
+        .ok_or_else(|| error::ErrorNotFound(format!("unknown query '{}'", name)))?;
+    let principal = auth::authenticated(&req);
+    if !rest::allowed(&stored.roles, rest::roles(&config, principal.as_deref())) {
+        return Err(error::ErrorForbidden(format!(
+            "'{}' may not run '{}'",
+            principal.as_deref().unwrap_or(audit::ANONYMOUS),
+            name
+        )));
+    }
+    let values = stored.values(&query).map_err(error::ErrorBadRequest)?;
+    let item = ReqTransactionItem {
+        query: Some(stored.sql.clone()),
+        values: Some(Value::Array(values)),
+        tag: Some(name),
+        cache_ttl_secs: stored.cache.as_ref().map(|c| c.ttl_secs),
+        cache_tables: stored.cache.as_ref().map(|c| c.tables.clone()).unwrap_or_default(),
+        ..Default::default()
+    };
+    let rows = match run_item(&req, &config, &profile_name, item).await? {
+        ResponseItem::QuerySuccess { result_set } => result_set,
+        ResponseItem::StatementSuccess { .. } => vec![],
+    };
+    let body = serde_json::to_vec(&rows).map_err(error::ErrorInternalServerError)?;
+    Ok(http_cache::respond(&req, body, stored.max_age_secs))
+}
@@ -0,0 +1,293 @@
+// Original copyright notice
+// Copyright (c) 2023-, Germano Rizzo <oss /AT/ germanorizzo /DOT/ it>
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+// This work was inspired by sqliterg by Germano Rizzo and heavily modified to suite mysql.
+// Modifications c) 2023-, Nexon Asia Pacific also under apache 2.0 license
+
+// Tracing spans for requests and items. Without the otel feature everything here is a
+// no-op, so callers don't need any cfg of their own.
+
+use std::{
+    future::Future,
+    sync::atomic::{AtomicBool, Ordering},
+};
+
+use serde::Deserialize;
+
+use crate::{
+    params::redact_literals,
+    req_res::{Response, ResponseEnum, ResponseItem},
+};
+
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(rename_all = "camelCase", deny_unknown_fields)]
+pub struct TelemetrySettings {
+    // OTLP/HTTP traces endpoint. Unset uses OTEL_EXPORTER_OTLP_ENDPOINT, or the collector
+    // on localhost:4318.
+    pub endpoint: Option<String>,
+    #[serde(default = "default_service_name")]
+    pub service_name: String,
+    // Replace literals in db.statement with ?, for SQL that inlines values
+    #[serde(default)]
+    pub redact_statements: bool,
+}
+
+impl Default for TelemetrySettings {
+    fn default() -> Self {
+        TelemetrySettings {
+            endpoint: None,
+            service_name: default_service_name(),
+            redact_statements: false,
+        }
+    }
+}
+
+fn default_service_name() -> String {
+    "sqlxrg".to_string()
+}
+
+#[cfg_attr(not(feature = "otel"), allow(dead_code))]
+static REDACT_STATEMENTS: AtomicBool = AtomicBool::new(false);
+
+#[cfg_attr(not(feature = "otel"), allow(dead_code))]
+fn statement_text(sql: &str) -> String {
+    if REDACT_STATEMENTS.load(Ordering::Relaxed) {
+        redact_literals(sql)
+    } else {
+        sql.to_string()
+    }
+}
+
+// Rows returned and affected, and the first error, over a set of results
+#[cfg_attr(not(feature = "otel"), allow(dead_code))]
+fn summarize(results: &[ResponseEnum]) -> (u64, u64, Option<String>) {
+    let mut returned = 0;
+    let mut affected = 0;
+    let mut error = None;
+    for result in results {
+        match result {
+            ResponseEnum::ResponseItem {
+                tag: _,
+                response_item: ResponseItem::QuerySuccess { result_set },
+            } => returned += result_set.len() as u64,
+            ResponseEnum::ResponseItem {
+                tag: _,
+                response_item: ResponseItem::StatementSuccess { rows_affected, .. },
+            } => affected += rows_affected,
+            ResponseEnum::Error { tag: _, error: e } => {
+                error.get_or_insert_with(|| e.to_string());
+            }
+        }
+    }
+    (returned, affected, error)
+}
+
+// Runs fut as the current span, then records how it went
+pub async fn traced<F>(span: Span, fut: F) -> Result<Response, actix_web::Error>
+where
+    F: Future<Output = Result<Response, actix_web::Error>>,
+{
+    let result = span.scope(fut).await;
+    match &result {
+        Ok(response) => span.results(response.results.as_deref().unwrap_or_default()),
+        Err(e) => span.error(e),
+    }
+    result
+}
+
+#[cfg(feature = "otel")]
+mod otel {
+    use std::{
+        borrow::Cow,
+        fmt::Display,
+        future::Future,
+        sync::{atomic::Ordering, OnceLock},
+    };
+
+    use actix_web::http::header::HeaderMap;
+    use opentelemetry::{
+        context::FutureExt,
+        global,
+        propagation::Extractor,
+        trace::{Status, TraceContextExt, Tracer},
+        Context, KeyValue,
+    };
+    use opentelemetry_otlp::{SpanExporter, WithExportConfig};
+    use opentelemetry_sdk::{
+        propagation::TraceContextPropagator, trace::SdkTracerProvider, Resource,
+    };
+
+    use super::{statement_text, summarize, TelemetrySettings, REDACT_STATEMENTS};
+    use crate::req_res::ResponseEnum;
+
+    const TRACER: &str = "sqlxrg";
+
+    static PROVIDER: OnceLock<SdkTracerProvider> = OnceLock::new();
+
+    // Built with the feature means traced, the settings only tune it
+    pub fn init(settings: Option<&TelemetrySettings>) -> Result<(), String> {
+        let default_settings = TelemetrySettings::default();
+        let settings = settings.unwrap_or(&default_settings);
+        let mut exporter = SpanExporter::builder().with_http();
+        if let Some(endpoint) = &settings.endpoint {
+            exporter = exporter.with_endpoint(endpoint);
+        }
+        let exporter = exporter
+            .build()
+            .map_err(|e| format!("could not create the OTLP exporter: {}", e))?;
+        let provider = SdkTracerProvider::builder()
+            .with_batch_exporter(exporter)
+            .with_resource(
+                Resource::builder()
+                    .with_service_name(settings.service_name.clone())
+                    .build(),
+            )
+            .build();
+        REDACT_STATEMENTS.store(settings.redact_statements, Ordering::Relaxed);
+        global::set_text_map_propagator(TraceContextPropagator::new());
+        global::set_tracer_provider(provider.clone());
+        let _ = PROVIDER.set(provider);
+        Ok(())
+    }
+
+    // Flushes the spans still in the batch
+    pub fn shutdown() {
+        if let Some(provider) = PROVIDER.get() {
+            if let Err(e) = provider.shutdown() {
+                log::warn!("Could not flush traces: {}", e);
+            }
+        }
+    }
+
+    struct HeaderExtractor<'a>(&'a HeaderMap);
+
+    impl Extractor for HeaderExtractor<'_> {
+        fn get(&self, key: &str) -> Option<&str> {
+            self.0.get(key).and_then(|v| v.to_str().ok())
+        }
+
+        fn keys(&self) -> Vec<&str> {
+            self.0.keys().map(|k| k.as_str()).collect()
+        }
+    }
+
+    pub struct Span {
+        cx: Context,
+    }
+
+    impl Span {
+        fn start(name: &'static str, parent: &Context) -> Span {
+            let tracer = global::tracer(TRACER);
+            let span = tracer
+                .span_builder(Cow::Borrowed(name))
+                .with_attributes([KeyValue::new("db.system", "mysql")])
+                .start_with_context(&tracer, parent);
+            Span {
+                cx: parent.with_span(span),
+            }
+        }
+
+        // Root of a request, continuing the caller's trace when it sent a traceparent
+        pub fn request(name: &'static str, headers: &HeaderMap) -> Span {
+            let parent =
+                global::get_text_map_propagator(|p| p.extract(&HeaderExtractor(headers)));
+            Span::start(name, &parent)
+        }
+
+        // Child of whatever span is current
+        pub fn child(name: &'static str) -> Span {
+            Span::start(name, &Context::current())
+        }
+
+        pub fn statement(&self, sql: &str) {
+            self.cx
+                .span()
+                .set_attribute(KeyValue::new("db.statement", statement_text(sql)));
+        }
+
+        pub fn results(&self, results: &[ResponseEnum]) {
+            let (returned, affected, error) = summarize(results);
+            let span = self.cx.span();
+            span.set_attribute(KeyValue::new("db.rows_returned", returned as i64));
+            span.set_attribute(KeyValue::new("db.rows_affected", affected as i64));
+            if let Some(error) = error {
+                self.error(error);
+            }
+        }
+
+        pub fn error(&self, error: impl Display) {
+            let span = self.cx.span();
+            span.set_attribute(KeyValue::new("error", true));
+            span.set_status(Status::error(error.to_string()));
+        }
+
+        pub fn scope<F: Future>(&self, fut: F) -> impl Future<Output = F::Output> {
+            fut.with_context(self.cx.clone())
+        }
+    }
+
+    impl Drop for Span {
+        fn drop(&mut self) {
+            self.cx.span().end();
+        }
+    }
+}
+
+#[cfg(feature = "otel")]
+pub use otel::{init, shutdown, Span};
+
+#[cfg(not(feature = "otel"))]
+mod noop {
+    use std::{fmt::Display, future::Future};
+
+    use actix_web::http::header::HeaderMap;
+
+    use super::TelemetrySettings;
+    use crate::req_res::ResponseEnum;
+
+    pub fn init(settings: Option<&TelemetrySettings>) -> Result<(), String> {
+        if settings.is_some() {
+            log::warn!("Telemetry is configured, but this build doesn't have the otel feature");
+        }
+        Ok(())
+    }
+
+    pub fn shutdown() {}
+
+    pub struct Span;
+
+    impl Span {
+        pub fn request(_name: &'static str, _headers: &HeaderMap) -> Span {
+            Span
+        }
+
+        pub fn child(_name: &'static str) -> Span {
+            Span
+        }
+
+        pub fn statement(&self, _sql: &str) {}
+
+        pub fn results(&self, _results: &[ResponseEnum]) {}
+
+        pub fn error(&self, _error: impl Display) {}
+
+        pub fn scope<F: Future>(&self, fut: F) -> impl Future<Output = F::Output> {
+            fut
+        }
+    }
+}
+
+#[cfg(not(feature = "otel"))]
+pub use noop::{init, shutdown, Span};
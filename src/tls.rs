@@ -0,0 +1,202 @@
+// Original copyright notice
+// Copyright (c) 2023-, Germano Rizzo <oss /AT/ germanorizzo /DOT/ it>
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+// This work was inspired by sqliterg by Germano Rizzo and heavily modified to suite mysql.
+// Modifications c) 2023-, Nexon Asia Pacific also under apache 2.0 license
+
+use std::{
+    any::Any,
+    collections::BTreeMap,
+    fmt,
+    sync::{Arc, RwLock},
+};
+
+use actix_tls::accept::rustls_0_23::TlsStream;
+use actix_web::{dev::Extensions, rt::net::TcpStream};
+use rustls::{
+    crypto::{ring, CryptoProvider},
+    pki_types::{pem::PemObject, CertificateDer, PrivateKeyDer},
+    server::{ClientHello, ResolvesServerCert, WebPkiClientVerifier},
+    sign::CertifiedKey,
+    RootCertStore, ServerConfig, SupportedProtocolVersion,
+};
+use serde::Deserialize;
+use x509_parser::parse_x509_certificate;
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Deserialize)]
+pub enum TlsVersion {
+    #[default]
+    #[serde(rename = "1.2")]
+    Tls12,
+    #[serde(rename = "1.3")]
+    Tls13,
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(rename_all = "camelCase", deny_unknown_fields)]
+pub struct TlsSettings {
+    // PEM files, the certificate one holding the full chain
+    pub cert_file: String,
+    pub key_file: String,
+    // Oldest TLS version accepted
+    #[serde(default)]
+    pub min_version: TlsVersion,
+    pub client_auth: Option<ClientAuthSettings>,
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(rename_all = "camelCase", deny_unknown_fields)]
+pub struct ClientAuthSettings {
+    // PEM bundle of the CAs client certificates must chain to
+    pub ca_file: String,
+    // Reject connections without a client certificate, otherwise they are anonymous
+    #[serde(default = "default_required")]
+    pub required: bool,
+    // Certificate common name to principal. Names not listed are used as they are.
+    #[serde(default)]
+    pub principals: BTreeMap<String, String>,
+}
+
+fn default_required() -> bool {
+    true
+}
+
+// Who a verified client certificate belongs to, stored in the connection data
+#[derive(Debug, Clone)]
+pub struct ClientPrincipal(pub String);
+
+fn provider() -> Arc<CryptoProvider> {
+    Arc::new(ring::default_provider())
+}
+
+fn read(path: &str) -> Result<Vec<u8>, String> {
+    let path = shellexpand::tilde(path).into_owned();
+    std::fs::read(&path).map_err(|e| format!("could not read '{}': {}", path, e))
+}
+
+fn load_certs(path: &str) -> Result<Vec<CertificateDer<'static>>, String> {
+    let certs = CertificateDer::pem_slice_iter(&read(path)?)
+        .collect::<Result<Vec<_>, _>>()
+        .map_err(|e| format!("could not parse '{}': {}", path, e))?;
+    if certs.is_empty() {
+        return Err(format!("no certificate in '{}'", path));
+    }
+    Ok(certs)
+}
+
+fn load_key(settings: &TlsSettings) -> Result<Arc<CertifiedKey>, String> {
+    let certs = load_certs(&settings.cert_file)?;
+    let key = PrivateKeyDer::from_pem_slice(&read(&settings.key_file)?)
+        .map_err(|e| format!("could not parse '{}': {}", settings.key_file, e))?;
+    CertifiedKey::from_der(certs, key, &provider())
+        .map(Arc::new)
+        .map_err(|e| format!("unusable certificate or key: {}", e))
+}
+
+// Hands out the current certificate, swapped in place when reloaded so new connections
+// pick it up without a restart
+pub struct CertResolver {
+    settings: TlsSettings,
+    key: RwLock<Arc<CertifiedKey>>,
+}
+
+impl fmt::Debug for CertResolver {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("CertResolver")
+            .field("cert_file", &self.settings.cert_file)
+            .finish()
+    }
+}
+
+impl ResolvesServerCert for CertResolver {
+    fn resolve(&self, _client_hello: ClientHello<'_>) -> Option<Arc<CertifiedKey>> {
+        Some(self.key.read().unwrap().clone())
+    }
+}
+
+impl CertResolver {
+    // A broken file leaves the old certificate in use
+    pub fn reload(&self) -> Result<(), String> {
+        let key = load_key(&self.settings)?;
+        *self.key.write().unwrap() = key;
+        Ok(())
+    }
+}
+
+pub fn resolver(settings: &TlsSettings) -> Result<Arc<CertResolver>, String> {
+    Ok(Arc::new(CertResolver {
+        settings: settings.clone(),
+        key: RwLock::new(load_key(settings)?),
+    }))
+}
+
+// One per https listener, all sharing the resolver. Only mtls listeners hold clients to
+// clientAuth.required, the others take a certificate when offered but never demand one.
+pub fn server_config(
+    settings: &TlsSettings,
+    resolver: &Arc<CertResolver>,
+    mtls: bool,
+) -> Result<ServerConfig, String> {
+    let versions: &[&SupportedProtocolVersion] = match settings.min_version {
+        TlsVersion::Tls12 => &[&rustls::version::TLS13, &rustls::version::TLS12],
+        TlsVersion::Tls13 => &[&rustls::version::TLS13],
+    };
+    let builder = ServerConfig::builder_with_provider(provider())
+        .with_protocol_versions(versions)
+        .map_err(|e| e.to_string())?;
+    let builder = match &settings.client_auth {
+        Some(client_auth) => {
+            let mut roots = RootCertStore::empty();
+            for cert in load_certs(&client_auth.ca_file)? {
+                roots
+                    .add(cert)
+                    .map_err(|e| format!("bad CA in '{}': {}", client_auth.ca_file, e))?;
+            }
+            let verifier = WebPkiClientVerifier::builder_with_provider(Arc::new(roots), provider());
+            let verifier = if mtls && client_auth.required {
+                verifier
+            } else {
+                verifier.allow_unauthenticated()
+            };
+            builder.with_client_cert_verifier(verifier.build().map_err(|e| e.to_string())?)
+        }
+        None => builder.with_no_client_auth(),
+    };
+    let mut config = builder.with_cert_resolver(resolver.clone());
+    config.alpn_protocols = vec![b"h2".to_vec(), b"http/1.1".to_vec()];
+    Ok(config)
+}
+
+// Common name of the leaf certificate, rustls has already verified the chain
+pub fn principal(cert: &CertificateDer<'_>, settings: &ClientAuthSettings) -> Option<String> {
+    let (_, cert) = parse_x509_certificate(cert.as_ref()).ok()?;
+    let cn = cert.subject().iter_common_name().next()?.as_str().ok()?;
+    Some(settings.principals.get(cn).cloned().unwrap_or_else(|| cn.to_string()))
+}
+
+// For HttpServer::on_connect, so handlers can find the client's principal
+pub fn on_connect(conn: &dyn Any, ext: &mut Extensions, settings: &ClientAuthSettings) {
+    let Some(stream) = conn.downcast_ref::<TlsStream<TcpStream>>() else {
+        return;
+    };
+    let (_, session) = stream.get_ref();
+    if let Some(principal) = session
+        .peer_certificates()
+        .and_then(|certs| certs.first())
+        .and_then(|cert| principal(cert, settings))
+    {
+        ext.insert(ClientPrincipal(principal));
+    }
+}
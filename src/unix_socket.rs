@@ -0,0 +1,85 @@
+// Original copyright notice
+// Copyright (c) 2023-, Germano Rizzo <oss /AT/ germanorizzo /DOT/ it>
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+// This work was inspired by sqliterg by Germano Rizzo and heavily modified to suite mysql.
+// Modifications c) 2023-, Nexon Asia Pacific also under apache 2.0 license
+
+use std::{
+    fs::{self, DirBuilder, Permissions},
+    io,
+    os::unix::{
+        fs::{chown, DirBuilderExt, FileTypeExt, PermissionsExt},
+        net::{UnixListener, UnixStream},
+    },
+    path::{Path, PathBuf},
+    process,
+};
+
+use crate::commandline::Owner;
+
+// Bound in a private directory and moved into place once its mode and owner are set, so
+// the socket is never reachable with the umask's permissions
+pub fn bind(path: &Path, mode: u32, owner: Option<Owner>) -> io::Result<UnixListener> {
+    remove_stale(path)?;
+    let name = path.file_name().ok_or_else(|| {
+        io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("{} is not a socket path", path.display()),
+        )
+    })?;
+    let mut private = PathBuf::from(path.parent().unwrap_or(Path::new(".")));
+    private.push(format!(".{}.{}", name.to_string_lossy(), process::id()));
+    DirBuilder::new().mode(0o700).create(&private)?;
+    let bound = (|| {
+        let staged = private.join(name);
+        let listener = UnixListener::bind(&staged)?;
+        set_access(&staged, mode, owner)?;
+        fs::rename(&staged, path)?;
+        Ok(listener)
+    })();
+    fs::remove_dir_all(&private)?;
+    bound
+}
+
+// A socket left behind by a previous run would make the bind fail, one a running
+// instance still answers on is not ours to take. Anything that isn't a socket is left
+// alone.
+pub fn remove_stale(path: &Path) -> io::Result<()> {
+    match fs::symlink_metadata(path) {
+        Ok(meta) if meta.file_type().is_socket() => match UnixStream::connect(path) {
+            Ok(_) => Err(io::Error::new(
+                io::ErrorKind::AddrInUse,
+                format!("{} is in use by a running server", path.display()),
+            )),
+            Err(e) if e.kind() == io::ErrorKind::ConnectionRefused => fs::remove_file(path),
+            Err(e) => Err(e),
+        },
+        Ok(_) => Err(io::Error::new(
+            io::ErrorKind::AlreadyExists,
+            format!("{} exists and is not a socket", path.display()),
+        )),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(()),
+        Err(e) => Err(e),
+    }
+}
+
+// Connecting needs write permission on the socket, so the mode decides who may connect
+fn set_access(path: &Path, mode: u32, owner: Option<Owner>) -> io::Result<()> {
+    fs::set_permissions(path, Permissions::from_mode(mode))?;
+    if let Some(owner) = owner {
+        chown(path, owner.uid, owner.gid)?;
+    }
+    Ok(())
+}